@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::net::{ Ipv4Addr, Ipv6Addr };
+
+use rocket::http::Status;
+use rocket::response::content::RawJson;
+
+use serde::Serialize;
+
+use crate::cache::CacheStats;
+
+use crate::dns::{ bns_address, bns_cache_stats, bns_domain_api, bns_negative_cache_stats, ipfs_cache_stats, upstream_cache_stats, is_bns_tld, metadata_cid, query_result_for, BnsApiDomain, MetadataValue, QueryResult, ResolveError, RECORD_TYPES };
+use crate::message::RCODE_NXDOMAIN;
+use crate::utils::{ extract_tld, svcb_record_to_string };
+
+//json apis, mostly for figuring out why a domain does (or doesn't) resolve without reading the logs
+
+//what the resolver makes of the domain. records are in the same format as in the metadata
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ResolverResult {
+  #[serde(rename = "CNAME")]
+  Cname {
+    cname: String,
+    ttl: u32,
+  },
+  Records {
+    a: Vec<String>,
+    aaaa: Vec<String>,
+    txt: Vec<String>,
+    mx: Vec<String>,
+    srv: Vec<String>,
+    svcb: Vec<String>,
+    https: Vec<String>,
+    //after clamping, for every type we serve
+    ttls: HashMap<&'static str, u32>,
+    redirect: Option<String>,
+  },
+  Zone,
+  NXDomain,
+  NonBns,
+  Error {
+    rcode: u16,
+    error: &'static str,
+  },
+}
+
+#[derive(Serialize)]
+struct DomainInfo {
+  //null if the domain doesn't resolve (unregistered, burned, or the BNS api or ipfs is down with nothing cached)
+  domain: Option<BnsApiDomain>,
+  metadata_cid: Option<String>,
+  metadata: Option<HashMap<String, MetadataValue>>,
+  //expired, but served anyways because getting a fresh one failed
+  stale: bool,
+  //seconds since it was cached
+  age: u32,
+  result: ResolverResult,
+}
+
+fn resolver_result(result: QueryResult) -> ResolverResult {
+  match result {
+    QueryResult::Cname(cname, ttl, _) => ResolverResult::Cname { cname, ttl },
+    QueryResult::Records(records) => ResolverResult::Records {
+      a: records.a.iter().map(|a| Ipv4Addr::from(*a).to_string()).collect(),
+      aaaa: records.aaaa.iter().map(|aaaa| Ipv6Addr::from(*aaaa).to_string()).collect(),
+      txt: records.txt.into_iter().chain(records.service_txt.iter().map(|txt| format!("{} {}", txt.service, txt.text))).collect(),
+      mx: records.mx.iter().map(|mx| format!("{} {}", mx.preference, mx.exchange)).collect(),
+      srv: records.srv.iter().map(|srv| format!("{} {} {} {} {}", srv.service, srv.priority, srv.weight, srv.port, srv.target)).collect(),
+      svcb: records.svcb.iter().map(svcb_record_to_string).collect(),
+      https: records.https.iter().map(svcb_record_to_string).collect(),
+      ttls: RECORD_TYPES.iter().map(|(type_name, rtype)| (*type_name, records.ttls.get(*rtype))).collect(),
+      redirect: records.redirect,
+    },
+    QueryResult::Zone => ResolverResult::Zone,
+    QueryResult::NXDomain => ResolverResult::NXDomain,
+    QueryResult::NonBns => ResolverResult::NonBns,
+  }
+}
+
+fn resolver_error(error: &ResolveError) -> ResolverResult {
+  ResolverResult::Error {
+    rcode: error.rcode(),
+    error: error.extended_error().1,
+  }
+}
+
+fn json_response(status: Status, domain_info: DomainInfo) -> (Status, RawJson<String>) {
+  (status, RawJson(serde_json::to_string(&domain_info).unwrap_or_default()))
+}
+
+//"prussia.ban", looked up the same way (and from the same caches) as for a dns query
+//404 if it doesn't exist (unregistered, burned, or not a BNS domain at all), 502 if the BNS api or ipfs is down, otherwise 200
+pub async fn domain_info(domain: &str) -> (Status, RawJson<String>) {
+  let (domain_name, tld) = extract_tld(domain);
+  if !is_bns_tld(tld) || domain_name.is_empty() {
+    let result = if is_bns_tld(tld) { ResolverResult::Zone } else { ResolverResult::NonBns };
+    return json_response(Status::NotFound, DomainInfo { domain: None, metadata_cid: None, metadata: None, stale: false, age: 0, result });
+  }
+  match bns_domain_api(domain_name.to_string(), tld.to_string()).await {
+    Ok(bns_domain) => {
+      let result = resolver_result(query_result_for(&bns_domain));
+      let metadata_cid = bns_domain.api_domain.metadata_hash.as_deref().map(metadata_cid);
+      json_response(Status::Ok, DomainInfo { domain: Some(bns_domain.api_domain), metadata_cid, metadata: Some(bns_domain.metadata), stale: bns_domain.stale, age: bns_domain.age, result })
+    },
+    Err(error) => {
+      let status = if error.rcode() == RCODE_NXDOMAIN { Status::NotFound } else { Status::BadGateway };
+      json_response(status, DomainInfo { domain: None, metadata_cid: None, metadata: None, stale: false, age: 0, result: resolver_error(&error) })
+    },
+  }
+}
+
+#[derive(Serialize)]
+struct AddressInfo {
+  domain: String,
+  address: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<&'static str>,
+}
+
+//"prussia.ban" -> its banano address (checksum checked), for wallets. same as the _ban.prussia.ban TXT record
+//404 if there is no (valid) address, 502 if the BNS api is down
+pub async fn address_info(domain: &str) -> (Status, RawJson<String>) {
+  let (domain_name, tld) = extract_tld(domain);
+  let (status, address, error) = if !is_bns_tld(tld) || domain_name.is_empty() {
+    (Status::NotFound, None, Some("Not a BNS domain"))
+  } else {
+    match bns_address(domain_name.to_string(), tld.to_string()).await {
+      Ok(Some(address)) => (Status::Ok, Some(address), None),
+      Ok(None) => (Status::NotFound, None, Some("BNS domain has no valid resolved address")),
+      Err(error) => {
+        let status = if error.rcode() == RCODE_NXDOMAIN { Status::NotFound } else { Status::BadGateway };
+        (status, None, Some(error.extended_error().1))
+      },
+    }
+  };
+  let address_info = AddressInfo { domain: domain.to_string(), address, error };
+  (status, RawJson(serde_json::to_string(&address_info).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct AllCacheStats {
+  bns: CacheStats,
+  bns_negative: CacheStats,
+  ipfs: CacheStats,
+  upstream: CacheStats,
+}
+
+//hits, misses, etc of the caches
+pub fn cache_stats() -> RawJson<String> {
+  let all_cache_stats = AllCacheStats {
+    bns: bns_cache_stats(),
+    bns_negative: bns_negative_cache_stats(),
+    ipfs: ipfs_cache_stats(),
+    upstream: upstream_cache_stats(),
+  };
+  RawJson(serde_json::to_string(&all_cache_stats).unwrap_or_default())
+}
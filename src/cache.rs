@@ -0,0 +1,221 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::hash::Hash;
+use std::time::{ Duration, Instant };
+
+use serde::Serialize;
+
+//size bounded cache, the least recently used entry gets evicted when it is full
+//entries can also expire after a while (a TTL), or never
+//expired entries can be kept for a while longer (max_stale), to use if getting a fresh one fails (rfc 8767)
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+  pub entries: usize,
+  pub capacity: usize,
+  pub hits: u64,
+  pub misses: u64,
+  pub stale_hits: u64,
+  pub evictions: u64,
+}
+
+struct Entry<V> {
+  value: V,
+  expires: Option<Instant>,
+  last_used: u64,
+}
+
+pub struct LruCache<K, V> {
+  capacity: usize,
+  max_stale: Duration,
+  entries: HashMap<K, Entry<V>>,
+  //last_used -> key, so the first one is the least recently used
+  order: BTreeMap<u64, K>,
+  //goes up on every use, instead of timestamps, so no two entries have the same last_used
+  tick: u64,
+  hits: u64,
+  misses: u64,
+  stale_hits: u64,
+  evictions: u64,
+}
+
+impl<V> Entry<V> {
+  //past its TTL and past max_stale, so of no use anymore
+  fn is_dead(&self, now: Instant, max_stale: Duration) -> bool {
+    self.expires.is_some_and(|expires| expires + max_stale <= now)
+  }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+  pub fn new(capacity: usize) -> LruCache<K, V> {
+    LruCache::with_max_stale(capacity, Duration::ZERO)
+  }
+
+  pub fn with_max_stale(capacity: usize, max_stale: Duration) -> LruCache<K, V> {
+    LruCache {
+      capacity,
+      max_stale,
+      entries: HashMap::new(),
+      order: BTreeMap::new(),
+      tick: 0,
+      hits: 0,
+      misses: 0,
+      stale_hits: 0,
+      evictions: 0,
+    }
+  }
+
+  //the entry (even if expired, as long as it isn't past max_stale), marked as just used
+  fn touch(&mut self, key: &K) -> Option<&Entry<V>> {
+    let now = Instant::now();
+    if self.entries.get(key)?.is_dead(now, self.max_stale) {
+      self.remove(key);
+      return None;
+    }
+    let entry = self.entries.get_mut(key)?;
+    self.order.remove(&entry.last_used);
+    self.tick += 1;
+    entry.last_used = self.tick;
+    self.order.insert(self.tick, key.clone());
+    Some(entry)
+  }
+
+  //expired entries are a miss
+  pub fn get(&mut self, key: &K) -> Option<V> {
+    let now = Instant::now();
+    match self.touch(key) {
+      Some(entry) if entry.expires.is_none_or(|expires| expires > now) => {
+        let value = entry.value.clone();
+        self.hits += 1;
+        Some(value)
+      },
+      _ => {
+        self.misses += 1;
+        None
+      },
+    }
+  }
+
+  //same as get, but expired entries are fine too (as long as they aren't past max_stale)
+  pub fn get_stale(&mut self, key: &K) -> Option<V> {
+    let value = self.touch(key)?.value.clone();
+    self.stale_hits += 1;
+    Some(value)
+  }
+
+  //same as get, but doesn't count as a use (or a hit or miss)
+  pub fn peek(&self, key: &K) -> Option<&V> {
+    let entry = self.entries.get(key)?;
+    entry.expires.is_none_or(|expires| expires > Instant::now()).then_some(&entry.value)
+  }
+
+  //whether get_stale would return something, without counting as a use
+  pub fn contains_stale(&self, key: &K) -> bool {
+    self.entries.get(key).is_some_and(|entry| !entry.is_dead(Instant::now(), self.max_stale))
+  }
+
+  //None for a ttl means it never expires (but can still be evicted)
+  pub fn insert(&mut self, key: K, value: V, ttl: Option<Duration>) {
+    self.remove(&key);
+    if self.capacity == 0 {
+      return;
+    }
+    while self.entries.len() >= self.capacity {
+      let Some((_, oldest)) = self.order.pop_first() else {
+        break;
+      };
+      self.entries.remove(&oldest);
+      self.evictions += 1;
+    }
+    self.tick += 1;
+    self.order.insert(self.tick, key.clone());
+    self.entries.insert(key, Entry {
+      value,
+      expires: ttl.map(|ttl| Instant::now() + ttl),
+      last_used: self.tick,
+    });
+  }
+
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    let entry = self.entries.remove(key)?;
+    self.order.remove(&entry.last_used);
+    Some(entry.value)
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      entries: self.entries.len(),
+      capacity: self.capacity,
+      hits: self.hits,
+      misses: self.misses,
+      stale_hits: self.stale_hits,
+      evictions: self.evictions,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread::sleep;
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let mut cache = LruCache::new(2);
+    cache.insert(1, "a", None);
+    cache.insert(2, "b", None);
+    //1 is now the most recently used, so 2 goes
+    assert_eq!(cache.get(&1), Some("a"));
+    cache.insert(3, "c", None);
+    assert_eq!(cache.get(&2), None);
+    assert_eq!(cache.get(&1), Some("a"));
+    assert_eq!(cache.get(&3), Some("c"));
+    assert_eq!(cache.stats().evictions, 1);
+    assert_eq!(cache.order.len(), cache.entries.len());
+  }
+
+  #[test]
+  fn expired_entries_are_only_stale() {
+    let mut cache = LruCache::with_max_stale(2, Duration::from_millis(50));
+    cache.insert(1, "a", Some(Duration::ZERO));
+    assert_eq!(cache.get(&1), None);
+    assert!(cache.contains_stale(&1));
+    assert_eq!(cache.get_stale(&1), Some("a"));
+    sleep(Duration::from_millis(60));
+    assert!(!cache.contains_stale(&1));
+    assert_eq!(cache.get_stale(&1), None);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.stale_hits), (0, 1, 1));
+  }
+
+  #[test]
+  fn dead_entries_are_removed() {
+    let mut cache = LruCache::new(2);
+    cache.insert(1, "a", Some(Duration::ZERO));
+    assert_eq!(cache.get(&1), None);
+    assert!(cache.entries.is_empty());
+    assert!(cache.order.is_empty());
+    //and don't take up room anymore
+    cache.insert(2, "b", None);
+    cache.insert(3, "c", None);
+    assert_eq!(cache.stats().evictions, 0);
+  }
+
+  #[test]
+  fn insert_overwrites() {
+    let mut cache = LruCache::new(2);
+    cache.insert(1, "a", None);
+    cache.insert(1, "b", None);
+    assert_eq!(cache.get(&1), Some("b"));
+    assert_eq!(cache.stats().entries, 1);
+    assert_eq!(cache.order.len(), 1);
+    assert_eq!(cache.stats().evictions, 0);
+  }
+
+  #[test]
+  fn zero_capacity_keeps_nothing() {
+    let mut cache = LruCache::new(0);
+    cache.insert(1, "a", None);
+    assert_eq!(cache.get(&1), None);
+    assert_eq!(cache.stats().entries, 0);
+  }
+}
@@ -1,5 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::io::Cursor;
+use std::path::{ Path, PathBuf };
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, Instant };
 
 use rocket::Response;
 use rocket::response;
@@ -9,52 +13,111 @@ use rocket::request::Request;
 
 use serde::{ Serialize, Deserialize };
 
-use reqwest::Client;
+use reqwest::{ Client, StatusCode };
 use reqwest::header::{ HeaderMap, ACCEPT, CONTENT_TYPE };
 
 use crate::utils::*;
+use crate::cache::{ CacheStats, LruCache };
+use crate::message::{ Edns, Header, Message, Name, RData, ResourceRecord, SvcbData, CLASS_IN, EDE_INVALID_DATA, EDE_NETWORK_ERROR, EDE_NO_REACHABLE_AUTHORITY, EDE_OTHER, EDE_PROHIBITED, EDE_STALE_ANSWER, EDNS_OPTION_EDE, EDNS_OPTION_PADDING, OPCODE_QUERY, RCODE_BADVERS, RCODE_FORMERR, RCODE_NOERROR, RCODE_NOTIMP, RCODE_NXDOMAIN, RCODE_REFUSED, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_CNAME, TYPE_HTTPS, TYPE_MX, TYPE_NS, TYPE_OPT, TYPE_SOA, TYPE_SRV, TYPE_SVCB, TYPE_TXT };
 
 //https://dns.elintra.net/dns-query
 
-//pub const SELF_CNAME: &'static str = "dns.elintra.net";
-pub const SELF_HOST: &'static str = "127.0.0.1";
+//pub const SELF_CNAME: &str = "dns.elintra.net";
+pub const SELF_HOST: &str = "127.0.0.1";
 pub const SELF_IP: [u8; 4] = [127, 0, 0, 1];
 /*
  * - https://query.hdns.io/dns-query
  * - https://dns.mullvad.net/dns-query 
 */
-const NON_BNS_DOH: &'static str = "https://mozilla.cloudflare-dns.com/dns-query";
-const BNS_API: &'static str = "https://api.creeper.banano.cc/banano/v1/account/bns";
-//const IPFS_API: &'static str = "https://ipfs.oversas.org/ipfs/";
-const IPFS_API: &'static str = "http://localhost:8080/ipfs/";
-const TLDS: [&'static str; 3] = ["mictest", "ban", "jtv"];
+const NON_BNS_DOH: &str = "https://mozilla.cloudflare-dns.com/dns-query";
+const BNS_API: &str = "https://api.creeper.banano.cc/banano/v1/account/bns";
+//const IPFS_API: &str = "https://ipfs.oversas.org/ipfs/";
+const IPFS_API: &str = "http://localhost:8080/ipfs/";
+//lowercase, see is_bns_tld
+pub const TLDS: [&str; 3] = ["mictest", "ban", "jtv"];
+//the browser will give up on us long before a request with no timeout would
+const BNS_API_TIMEOUT: Duration = Duration::from_secs(5);
+const IPFS_TIMEOUT: Duration = Duration::from_secs(5);
+
+//_ban.prussia.ban TXT is the banano address prussia.ban resolves to, for wallets
+const ADDRESS_SERVICE: &str = "_ban";
+
+//the metadata keys of the records we serve
+pub const RECORD_TYPES: [(&str, u16); 8] = [("A", TYPE_A), ("AAAA", TYPE_AAAA), ("CNAME", TYPE_CNAME), ("TXT", TYPE_TXT), ("MX", TYPE_MX), ("SRV", TYPE_SRV), ("SVCB", TYPE_SVCB), ("HTTPS", TYPE_HTTPS)];
 
 //rfc 1035 (section 4, section 7.3)
 //rfc 8484
 //www.tcpipguide.com/free/t_DNSMessageHeaderandQuestionSectionFormat-2.htm
 
-//16 bits (2 bytes)
-/*
-fn extract_type_bytes_from_dns_query(dns_query: &[u8]) -> u16 {
-  //
-  let length = dns_query[12];
-  if length >= 192 {
-    ((dns_query[13] as u16) << 8) | (dns_query[14] as u16)
-  } else {
-    ((dns_query[12 + length as usize] as u16) << 8) | (dns_query[12 + length as usize + 1] as u16)
-  }
-}
-*/
+//a BNS api or ipfs outage should be retried soon, not cached like an NXDOMAIN
+const SERVFAIL_TTL: u32 = 5;
+//rfc 6891. 1232 is what dns flag day 2020 settled on, avoids fragmentation
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+pub const EDNS_VERSION: u8 = 0;
+//rfc 8467 section 4.1, the recommended block size for padding responses
+const PADDING_BLOCK_SIZE: usize = 468;
+
+//TTL if the metadata doesn't set one (arbitrarily pick 10 minutes, or 600 seconds)
+const DEFAULT_TTL: u32 = 600;
+//TTLs from metadata get clamped to this range
+//too low and every page load hits the BNS api, too high and domain owners can't change anything
+const MIN_TTL: u32 = 30;
+const MAX_TTL: u32 = 86400;
+//there is no real zone behind the BNS TLDs, so the SOA and NS we hand out for them are synthesized (rfc 1035 section 3.3.13)
+//the timers are arbitrary, nothing ever does a zone transfer from us
+const SOA_REFRESH: u32 = 3600;
+const SOA_RETRY: u32 = 600;
+const SOA_EXPIRE: u32 = 86400;
+//SOA MINIMUM, which is how long NXDOMAIN and NODATA answers get cached for (rfc 2308 section 5)
+const NEGATIVE_TTL: u32 = 300;
+//the nameservers we say the BNS TLDs have (the first one is also the SOA MNAME), change to whatever hostname(s) point to this server
+const NAMESERVERS: [&str; 1] = ["localhost"];
+const NS_TTL: u32 = 86400;
+//how many BNS domains to keep around, so every A, AAAA and HTTPS query of a page load doesn't hit the BNS api and ipfs
+//each one is kept for the smallest TTL it has
+const BNS_CACHE_CAPACITY: usize = 10000;
+//unregistered (and burned) domains, kept for NEGATIVE_TTL like any other resolver would (rfc 2308 section 5)
+//separate, so lots of typos can't push out the domains that do exist
+const BNS_NEGATIVE_CACHE_CAPACITY: usize = 10000;
+//serve-stale (rfc 8767): if the BNS api or ipfs is down, keep answering with what we got last time, for up to this long after it expired
+//(the rfc suggests 1 to 3 days)
+const BNS_MAX_STALE: Duration = Duration::from_secs(86400);
+//TTL of stale answers, short so clients come back soon for a fresh one (rfc 8767 section 4)
+const STALE_TTL: u32 = 30;
+//rfc 8914, so clients can tell stale answers from fresh ones
+const STALE_EXTENDED_ERROR: (u16, &str) = (EDE_STALE_ANSWER, "BNS API or IPFS metadata unreachable");
+//how often to retry getting a fresh answer while serving stale ones
+const STALE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+//metadata is addressed by its hash, so it never changes and never needs to expire, only be evicted when there are too many
+const IPFS_CACHE_CAPACITY: usize = 10000;
+//set to a directory (eg: Some("ipfs_cache")) to also keep metadata on disk, so it survives restarts and the ipfs daemon being down
+const IPFS_CACHE_DIR: Option<&str> = None;
+//answers from NON_BNS_DOH, kept for their smallest TTL (or the SOA minimum if negative)
+const UPSTREAM_CACHE_CAPACITY: usize = 10000;
 
 pub struct Answer {
   pub bytes: Option<Vec<u8>>,
 }
 
+//how long the http response can be cached: no longer than the smallest TTL in it (rfc 8484 section 5.1)
+//SERVFAILs have no TTLs, and shouldn't stick around anyways
+pub fn cache_max_age(rb: &[u8]) -> Option<u32> {
+  let message = Message::decode(rb).ok()?;
+  if message.header.rcode == RCODE_SERVFAIL as u8 {
+    return Some(SERVFAIL_TTL);
+  }
+  message.answers.iter().chain(&message.authorities).map(|record| record.ttl).min()
+}
+
 impl<'r> Responder<'r, 'static> for Answer {
   fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
     if let Some(rb) = self.bytes {
       println!("a {:?}", &rb);
-      Response::build().status(Status { code: 200 }).raw_header("Content-Type", "application/dns-message").raw_header("Accept", "application/dns-message").sized_body(rb.len(), Cursor::new(rb)).ok()
+      let mut response = Response::build();
+      if let Some(max_age) = cache_max_age(&rb) {
+        response.raw_header("Cache-Control", format!("max-age={}", max_age));
+      }
+      response.status(Status { code: 200 }).raw_header("Content-Type", "application/dns-message").raw_header("Accept", "application/dns-message").sized_body(rb.len(), Cursor::new(rb)).ok()
     } else {
       Response::build().status(Status { code: 400 }).ok()
     }
@@ -67,319 +130,975 @@ struct BnsApiPayload {
   tld: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)] //history is not read yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BnsApiDomain {
-  tld: String,
-  name: String,
+  pub tld: String,
+  pub name: String,
   #[serde(skip)]
   history: Vec<String>, //should really be a vec of history blocks
-  burned: Option<bool>,
-  metadata_hash: Option<String>,
-  resolved_address: Option<String>,
+  pub burned: Option<bool>,
+  pub metadata_hash: Option<String>,
+  pub resolved_address: Option<String>,
 }
 
+//no domain (or a 404) means it isn't registered
 #[derive(Deserialize)]
 pub struct BnsApiResponse {
-  domain: BnsApiDomain,
+  domain: Option<BnsApiDomain>,
+}
+
+//metadata values are usually strings, but records that can have several values can also be a list
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetadataValue {
+  String(String),
+  List(Vec<String>),
+  //numbers, objects, etc. we don't use them, but they shouldn't make the whole metadata fail to parse
+  Other(serde_json::Value),
+}
+
+impl MetadataValue {
+  pub fn as_str(&self) -> Option<&str> {
+    if let MetadataValue::String(value) = self {
+      Some(value)
+    } else {
+      None
+    }
+  }
+
+  //"v=spf1 -all" or ["v=spf1 -all", "_dnslink dnslink=/ipfs/..."], commas are left alone
+  pub fn list(&self) -> Vec<&str> {
+    match self {
+      MetadataValue::String(value) => vec![value],
+      MetadataValue::List(values) => values.iter().map(|value| value.as_str()).collect(),
+      MetadataValue::Other(_) => Vec::new(),
+    }
+  }
+
+  //"300" or 300
+  pub fn as_u32(&self) -> Option<u32> {
+    match self {
+      MetadataValue::String(value) => value.trim().parse::<u32>().ok(),
+      MetadataValue::Other(value) => value.as_u64().map(|value| value.min(u64::from(u32::MAX)) as u32),
+      MetadataValue::List(_) => None,
+    }
+  }
+
+  //"1.2.3.4, 5.6.7.8" or ["1.2.3.4", "5.6.7.8"]
+  pub fn split(&self) -> Vec<&str> {
+    match self {
+      MetadataValue::String(value) => value.split(",").map(|part| part.trim()).collect(),
+      MetadataValue::List(values) => values.iter().map(|value| value.trim()).collect(),
+      MetadataValue::Other(_) => Vec::new(),
+    }
+  }
 }
 
+#[derive(Clone)]
 pub struct BnsDomain {
   pub api_domain: BnsApiDomain,
-  pub metadata: HashMap<String, String>,
+  pub metadata: HashMap<String, MetadataValue>,
+  //expired, from the cache, because getting a fresh one failed. gets STALE_TTL instead of the TTLs in the metadata
+  pub stale: bool,
+  //seconds since it was cached, taken off the TTLs in the metadata so clients don't cache it for longer than the owner wants
+  pub age: u32,
 }
 
-pub enum QueryResult {
-  Cname(String),
-  A([u8; 4], Option<String>),
-  NXDomain,
-  NonBns,
+//"TTL" for all records, "TTL_A", "TTL_TXT", etc. to override it for one type
+#[derive(Clone)]
+pub struct Ttls {
+  default: u32,
+  per_type: HashMap<u16, u32>,
 }
 
-//start param is a different index to start at (used for pointers)
-pub fn extract_host_from_dns_query(dns_query: &[u8], start: Option<usize>) -> Result<String, ()> {
-  //13th byte should be start of question, indicate how long the label/zone is in bytes
-  let mut length_pos: usize = 12;
-  if start.is_some() {
-    length_pos = start.unwrap();
+impl Ttls {
+  pub fn from_metadata(metadata: &HashMap<String, MetadataValue>) -> Ttls {
+    let default = metadata.get("TTL").and_then(|ttl| ttl.as_u32()).map(|ttl| ttl.clamp(MIN_TTL, MAX_TTL)).unwrap_or(DEFAULT_TTL);
+    let mut per_type = HashMap::new();
+    for (type_name, rtype) in RECORD_TYPES {
+      if let Some(ttl) = metadata.get(&format!("TTL_{}", type_name)).and_then(|ttl| ttl.as_u32()) {
+        per_type.insert(rtype, ttl.clamp(MIN_TTL, MAX_TTL));
+      }
+    }
+    Ttls { default, per_type }
+  }
+
+  pub fn get(&self, rtype: u16) -> u32 {
+    *self.per_type.get(&rtype).unwrap_or(&self.default)
   }
-  let mut domain_name: Vec<String> = Vec::new();
-  let query_len = dns_query.len();
-  loop {
-    if length_pos >= query_len {
-      return Err(());
+
+  //the shortest any record is allowed to be cached for
+  pub fn min(&self) -> u32 {
+    self.per_type.values().copied().fold(self.default, u32::min)
+  }
+
+  //what is left of them after being cached for age seconds
+  pub fn aged(&self, age: u32) -> Ttls {
+    Ttls {
+      default: self.default.saturating_sub(age),
+      per_type: self.per_type.iter().map(|(rtype, ttl)| (*rtype, ttl.saturating_sub(age))).collect(),
     }
-    let length = dns_query[length_pos];
-    if length_pos + usize::from(length) >= dns_query.len() {
-      return Err(());
+  }
+}
+
+impl Default for Ttls {
+  fn default() -> Ttls {
+    Ttls { default: DEFAULT_TTL, per_type: HashMap::new() }
+  }
+}
+
+//"10 mail.prussia.ban" (preference, exchange)
+pub struct MxRecord {
+  pub preference: u16,
+  pub exchange: Name,
+}
+
+impl MxRecord {
+  pub fn parse(mx_record: &str) -> Option<MxRecord> {
+    let mut parts = mx_record.split_whitespace();
+    let preference = parts.next()?.parse::<u16>().ok()?;
+    let exchange = Name::from_dotted(parts.next()?).ok()?;
+    if parts.next().is_some() {
+      return None;
     }
-    if length == 0 {
-      //the last ., ended
-      break;
+    Some(MxRecord { preference, exchange })
+  }
+}
+
+//"_matrix._tcp 10 5 8448 matrix.prussia.ban" (service, priority, weight, port, target)
+//served for _matrix._tcp.prussia.ban, since the records of subdomains live in the metadata of the domain
+pub struct SrvRecord {
+  pub service: String,
+  pub priority: u16,
+  pub weight: u16,
+  pub port: u16,
+  pub target: Name,
+}
+
+impl SrvRecord {
+  pub fn parse(srv_record: &str) -> Option<SrvRecord> {
+    let mut parts = srv_record.split_whitespace();
+    let service = parts.next()?;
+    if split_service_labels(service) != (service, "") {
+      //every label of the service has to start with an underscore
+      return None;
     }
-    let label;
-    if length >= 192 {
-      //first two bits are 11, is pointer
-      let offset: usize = binary_to_u8(&to_binary(length, false)[2..]).into();
-      label = extract_host_from_dns_query(dns_query, Some(offset))?;
-    } else {
-      //ascii
-      let label_bytes = &dns_query[(length_pos + 1)..=(length_pos + usize::from(length))];
-      label = label_bytes.iter().map(|c_u8| char::from(c_u8.clone()).to_string()).collect::<Vec<String>>().join("");
+    let priority = parts.next()?.parse::<u16>().ok()?;
+    let weight = parts.next()?.parse::<u16>().ok()?;
+    let port = parts.next()?.parse::<u16>().ok()?;
+    let target = Name::from_dotted(parts.next()?).ok()?;
+    if parts.next().is_some() {
+      return None;
     }
-    domain_name.push(label);
-    length_pos = length_pos + usize::from(length) + 1;
-  }
-  return Ok(domain_name.join("."));
-}
-
-pub fn query_hostname_to_label_bytes(query_hostname: &str) -> Vec<u8> {
-  let mut label_bytes = Vec::new();
-  //length
-  //error if too long?
-  //
-  for label in query_hostname.split(".") {
-    label_bytes.push(label.len() as u8);
-    for c in label.chars() {
-      //convert to ascii
-      label_bytes.push(c as u8);
+    Some(SrvRecord { service: service.to_string(), priority, weight, port, target })
+  }
+}
+
+//"_dnslink dnslink=/ipfs/..." (service, text), served for _dnslink.prussia.ban, same as SRV
+//anything that doesn't start with underscore labels and a space is for the domain itself, eg: "v=spf1 -all"
+pub struct TxtRecord {
+  pub service: String,
+  pub text: String,
+}
+
+impl TxtRecord {
+  pub fn parse(txt_record: &str) -> TxtRecord {
+    if let Some((service, text)) = txt_record.split_once(' ') && !service.is_empty() && split_service_labels(service) == (service, "") {
+      return TxtRecord { service: service.to_string(), text: text.to_string() };
     }
+    TxtRecord { service: String::new(), text: txt_record.to_string() }
   }
-  label_bytes.push(0);
-  label_bytes
 }
 
-async fn bns_domain_api(domain_name: String, tld: String) -> reqwest::Result<BnsDomain> {
+//A and AAAA can both be set on the same domain, and each can have several addresses (round robin, failover)
+#[derive(Default)]
+pub struct BnsRecords {
+  pub a: Vec<[u8; 4]>,
+  pub aaaa: Vec<[u8; 16]>,
+  pub txt: Vec<String>,
+  //for names under the domain, eg: _dnslink.prussia.ban, _acme-challenge.prussia.ban
+  pub service_txt: Vec<TxtRecord>,
+  pub mx: Vec<MxRecord>,
+  pub srv: Vec<SrvRecord>,
+  pub svcb: Vec<SvcbData>,
+  pub https: Vec<SvcbData>,
+  pub ttls: Ttls,
+  //set when the domain points to us, and we send the browser on to here
+  pub redirect: Option<String>,
+  //from an expired cache entry, see BnsDomain.stale
+  pub stale: bool,
+}
+
+//why a BNS domain could not be resolved
+//only NotRegistered and Burned are NXDOMAIN, everything else might work on the next try, so is a SERVFAIL that doesn't get cached for long
+#[derive(Clone, Debug)]
+pub enum ResolveError {
+  //connection failed, timed out, or not a 2xx
+  BnsApiUnreachable,
+  //answered, but not with anything we understand
+  BnsApiInvalid,
+  //the BNS api says there is no such domain
+  NotRegistered,
+  //the domain exists, but its owner burned it, so it shouldn't resolve to anything anymore
+  Burned,
+  IpfsUnreachable,
+  //the metadata is not a json object
+  IpfsInvalid,
+}
+
+impl ResolveError {
+  pub fn rcode(&self) -> u16 {
+    match self {
+      ResolveError::NotRegistered | ResolveError::Burned => RCODE_NXDOMAIN,
+      _ => RCODE_SERVFAIL,
+    }
+  }
+
+  //rfc 8914 (INFO-CODE, EXTRA-TEXT)
+  pub fn extended_error(&self) -> (u16, &'static str) {
+    match self {
+      ResolveError::BnsApiUnreachable => (EDE_NO_REACHABLE_AUTHORITY, "BNS API unreachable"),
+      ResolveError::BnsApiInvalid => (EDE_INVALID_DATA, "BNS API returned an invalid response"),
+      //there is no "not found" info code, so other with text
+      ResolveError::NotRegistered => (EDE_OTHER, "Not Found: BNS domain is not registered"),
+      ResolveError::Burned => (EDE_OTHER, "BNS domain was burned"),
+      ResolveError::IpfsUnreachable => (EDE_OTHER, "IPFS metadata unreachable"),
+      ResolveError::IpfsInvalid => (EDE_INVALID_DATA, "IPFS metadata is not a JSON object"),
+    }
+  }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum QueryResult {
+  //target, TTL, and whether it is stale
+  Cname(String, u32, bool),
+  Records(BnsRecords),
+  //the TLD itself (eg: "ban."), we answer SOA and NS for it
+  Zone,
+  //only for names under a domain (eg: _matrix._tcp.prussia.ban) that have nothing, unregistered domains are ResolveError::NotRegistered
+  NXDomain,
+  NonBns,
+}
+
+//just what the BNS api knows about the domain, no metadata
+async fn bns_api_domain(domain_name: String, tld: String) -> Result<BnsApiDomain, ResolveError> {
   let client = Client::new();
-  let api_domain = (client.post(BNS_API).json(&BnsApiPayload {
-    domain_name,
-    tld,
-  }).send().await?.json::<BnsApiResponse>().await?).domain;
-  let mut metadata = HashMap::new();
-  //println!("{:?}", api_domain);
-  if let Some(ref metadata_hash) = api_domain.metadata_hash {
-    //the hex_to_base58 function is an atrocity
-    let metadata_hash = hex_to_base58("0".repeat(128 - 4 - 64).to_string() + "1220" + metadata_hash);
-    println!("{}", metadata_hash);
-    metadata = client.get(&format!("{}{}", IPFS_API, metadata_hash)).send().await?.json::<HashMap<String, String>>().await?;
-    println!("{:?}", metadata);
+  //names are case insensitive, and clients can randomize the case (dns 0x20), but the BNS api isn't
+  let res = client.post(BNS_API).json(&BnsApiPayload {
+    domain_name: domain_name.to_lowercase(),
+    tld: tld.to_lowercase(),
+  }).timeout(BNS_API_TIMEOUT).send().await.map_err(|_| ResolveError::BnsApiUnreachable)?;
+  if res.status() == StatusCode::NOT_FOUND {
+    return Err(ResolveError::NotRegistered);
+  } else if !res.status().is_success() {
+    return Err(ResolveError::BnsApiUnreachable);
   }
+  //decode errors are the api's fault, anything else (eg: timing out halfway through the body) is the network's
+  res.json::<BnsApiResponse>().await.map_err(|e| if e.is_decode() {
+    ResolveError::BnsApiInvalid
+  } else {
+    ResolveError::BnsApiUnreachable
+  })?.domain.ok_or(ResolveError::NotRegistered)
+}
+
+//the metadata hash is the sha256 digest in hex, the CIDv0 is the base58 of the multihash (0x12 sha256, 0x20 32 bytes long, then the digest)
+pub fn metadata_cid(metadata_hash: &str) -> String {
+  //the hex_to_base58 function is an atrocity
+  hex_to_base58("0".repeat(128 - 4 - 64).to_string() + "1220" + metadata_hash)
+}
+
+//CID -> metadata, see IPFS_CACHE_CAPACITY and IPFS_CACHE_DIR
+static IPFS_CACHE: LazyLock<Mutex<LruCache<String, HashMap<String, MetadataValue>>>> = LazyLock::new(|| Mutex::new(LruCache::new(IPFS_CACHE_CAPACITY)));
+
+pub fn ipfs_cache_stats() -> CacheStats {
+  IPFS_CACHE.lock().unwrap().stats()
+}
+
+//the CID is base58, so it is always a safe file name
+fn ipfs_cache_path(cid: &str) -> Option<PathBuf> {
+  IPFS_CACHE_DIR.map(|dir| Path::new(dir).join(format!("{}.json", cid)))
+}
+
+async fn read_ipfs_cache_file(cid: &str) -> Option<HashMap<String, MetadataValue>> {
+  let bytes = tokio::fs::read(ipfs_cache_path(cid)?).await.ok()?;
+  serde_json::from_slice(&bytes).ok()
+}
+
+//failing to write is fine, it just gets fetched again after a restart
+async fn write_ipfs_cache_file(cid: &str, bytes: &[u8]) {
+  let (Some(dir), Some(path)) = (IPFS_CACHE_DIR, ipfs_cache_path(cid)) else {
+    return;
+  };
+  if let Err(e) = tokio::fs::create_dir_all(dir).await {
+    println!("Could not create the IPFS cache directory: {}", e);
+  } else if let Err(e) = tokio::fs::write(path, bytes).await {
+    println!("Could not write to the IPFS cache: {}", e);
+  }
+}
+
+async fn fetch_ipfs_metadata(cid: &str) -> Result<HashMap<String, MetadataValue>, ResolveError> {
+  let client = Client::new();
+  let res = client.get(format!("{}{}", IPFS_API, cid)).timeout(IPFS_TIMEOUT).send().await.map_err(|_| ResolveError::IpfsUnreachable)?;
+  if !res.status().is_success() {
+    return Err(ResolveError::IpfsUnreachable);
+  }
+  let bytes = res.bytes().await.map_err(|_| ResolveError::IpfsUnreachable)?;
+  let metadata = serde_json::from_slice::<HashMap<String, MetadataValue>>(&bytes).map_err(|_| ResolveError::IpfsInvalid)?;
+  println!("{:?}", metadata);
+  //only once we know it is valid, otherwise a bad response would be stuck on disk forever
+  write_ipfs_cache_file(cid, &bytes).await;
+  Ok(metadata)
+}
+
+//cached in memory, and on disk if IPFS_CACHE_DIR is set
+async fn ipfs_metadata(api_domain: &BnsApiDomain) -> Result<HashMap<String, MetadataValue>, ResolveError> {
+  let Some(ref metadata_hash) = api_domain.metadata_hash else {
+    return Ok(HashMap::new());
+  };
+  let cid = metadata_cid(metadata_hash);
+  println!("{}", cid);
+  if let Some(metadata) = IPFS_CACHE.lock().unwrap().get(&cid) {
+    return Ok(metadata);
+  }
+  let metadata = match read_ipfs_cache_file(&cid).await {
+    Some(metadata) => metadata,
+    None => fetch_ipfs_metadata(&cid).await?,
+  };
+  IPFS_CACHE.lock().unwrap().insert(cid, metadata.clone(), None);
+  Ok(metadata)
+}
+
+//the banano address the domain resolves to. None if it doesn't have one,
+//or the one it has has a bad checksum (better nothing than someone sending to a typo)
+fn resolved_address(bns_domain: &BnsDomain) -> Option<String> {
+  bns_domain.api_domain.resolved_address.clone().filter(|address| {
+    let valid = is_valid_banano_address(address);
+    if !valid {
+      println!("Invalid resolved address: {}", address);
+    }
+    valid
+  })
+}
+
+//cached like any other lookup of the domain (burned domains are an error, see fetch_bns_domain)
+//the address doesn't need the metadata though, so if ipfs is what failed, ask the BNS api on its own
+//(not cached, but only happens while the metadata can't be fetched)
+async fn bns_address_domain(domain_name: String, tld: String) -> Result<BnsDomain, ResolveError> {
+  match bns_domain_api(domain_name.clone(), tld.clone()).await {
+    Err(ResolveError::IpfsUnreachable | ResolveError::IpfsInvalid) => {
+      let api_domain = bns_api_domain(domain_name, tld).await?;
+      if api_domain.burned == Some(true) {
+        return Err(ResolveError::Burned);
+      }
+      Ok(BnsDomain { api_domain, metadata: HashMap::new(), stale: false, age: 0 })
+    },
+    result => result,
+  }
+}
+
+pub async fn bns_address(domain_name: String, tld: String) -> Result<Option<String>, ResolveError> {
+  Ok(resolved_address(&bns_address_domain(domain_name, tld).await?))
+}
+
+//(domain name, tld), lowercased
+type BnsCacheKey = (String, String);
+
+//when it was cached (for decrementing the TTLs), and the domain
+static BNS_CACHE: LazyLock<Mutex<LruCache<BnsCacheKey, (Instant, BnsDomain)>>> = LazyLock::new(|| Mutex::new(LruCache::with_max_stale(BNS_CACHE_CAPACITY, BNS_MAX_STALE)));
+
+//same, when it was cached (for decrementing the SOA), and why it doesn't resolve
+static BNS_NEGATIVE_CACHE: LazyLock<Mutex<LruCache<BnsCacheKey, (Instant, ResolveError)>>> = LazyLock::new(|| Mutex::new(LruCache::new(BNS_NEGATIVE_CACHE_CAPACITY)));
+
+//domains being served stale, that are being refreshed in the background
+static BNS_REFRESHING: LazyLock<Mutex<HashSet<BnsCacheKey>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn bns_cache_stats() -> CacheStats {
+  BNS_CACHE.lock().unwrap().stats()
+}
+
+pub fn bns_negative_cache_stats() -> CacheStats {
+  BNS_NEGATIVE_CACHE.lock().unwrap().stats()
+}
+
+fn cache_bns_result(key: &BnsCacheKey, result: &Result<BnsDomain, ResolveError>) {
+  match result {
+    Ok(bns_domain) => {
+      let ttl = Ttls::from_metadata(&bns_domain.metadata).min();
+      BNS_CACHE.lock().unwrap().insert(key.clone(), (Instant::now(), bns_domain.clone()), Some(Duration::from_secs(ttl.into())));
+    },
+    Err(error) if error.rcode() == RCODE_NXDOMAIN => {
+      //a domain that got burned must not keep resolving from a stale answer either
+      BNS_CACHE.lock().unwrap().remove(key);
+      BNS_NEGATIVE_CACHE.lock().unwrap().insert(key.clone(), (Instant::now(), error.clone()), Some(Duration::from_secs(NEGATIVE_TTL.into())));
+    },
+    //outages (SERVFAIL) aren't cached, the next query should try again
+    Err(_) => {},
+  }
+}
+
+//for TTLs, which are u32 seconds
+fn secs_since(instant: Instant) -> u32 {
+  instant.elapsed().as_secs().min(u64::from(u32::MAX)) as u32
+}
+
+fn stale_bns_domain(key: &BnsCacheKey) -> Option<BnsDomain> {
+  let (cached_at, mut bns_domain) = BNS_CACHE.lock().unwrap().get_stale(key)?;
+  bns_domain.stale = true;
+  bns_domain.age = secs_since(cached_at);
+  Some(bns_domain)
+}
+
+//keeps trying to get a fresh answer for a domain that is being served stale,
+//until it does, finds out the domain is gone, or the stale answer is too old to serve anyways
+fn refresh_in_background(key: BnsCacheKey, domain_name: String, tld: String) {
+  if !BNS_REFRESHING.lock().unwrap().insert(key.clone()) {
+    //already on it
+    return;
+  }
+  tokio::spawn(async move {
+    loop {
+      tokio::time::sleep(STALE_REFRESH_INTERVAL).await;
+      if !BNS_CACHE.lock().unwrap().contains_stale(&key) {
+        //past BNS_MAX_STALE (or evicted), the next query will have to try again itself
+        break;
+      }
+      let result = fetch_bns_domain(domain_name.clone(), tld.clone()).await;
+      cache_bns_result(&key, &result);
+      if !matches!(result, Err(ref error) if error.rcode() == RCODE_SERVFAIL) {
+        break;
+      }
+    }
+    BNS_REFRESHING.lock().unwrap().remove(&key);
+  });
+}
+
+//cached, see BNS_CACHE_CAPACITY, BNS_NEGATIVE_CACHE_CAPACITY and BNS_MAX_STALE
+pub async fn bns_domain_api(domain_name: String, tld: String) -> Result<BnsDomain, ResolveError> {
+  let key = (domain_name.to_lowercase(), tld.to_lowercase());
+  if let Some((cached_at, mut bns_domain)) = BNS_CACHE.lock().unwrap().get(&key) {
+    bns_domain.age = secs_since(cached_at);
+    return Ok(bns_domain);
+  }
+  if let Some((_, error)) = BNS_NEGATIVE_CACHE.lock().unwrap().get(&key) {
+    return Err(error);
+  }
+  //already known to be down, don't make the client wait for it to time out again (rfc 8767 section 5)
+  //the background refresh will update the cache when it is back
+  let refreshing = BNS_REFRESHING.lock().unwrap().contains(&key);
+  if refreshing && let Some(bns_domain) = stale_bns_domain(&key) {
+    return Ok(bns_domain);
+  }
+  let result = fetch_bns_domain(domain_name.clone(), tld.clone()).await;
+  cache_bns_result(&key, &result);
+  match result {
+    Err(error) if error.rcode() == RCODE_SERVFAIL => {
+      let Some(bns_domain) = stale_bns_domain(&key) else {
+        return Err(error);
+      };
+      println!("Serving stale answer for {}.{}: {:?}", domain_name, tld, error);
+      refresh_in_background(key, domain_name, tld);
+      Ok(bns_domain)
+    },
+    result => result,
+  }
+}
+
+async fn fetch_bns_domain(domain_name: String, tld: String) -> Result<BnsDomain, ResolveError> {
+  let api_domain = bns_api_domain(domain_name, tld).await?;
+  //no point fetching the metadata, stale metadata or resolved_address must not make it resolve
+  if api_domain.burned == Some(true) {
+    return Err(ResolveError::Burned);
+  }
+  //println!("{:?}", api_domain);
+  let metadata = ipfs_metadata(&api_domain).await?;
   Ok(BnsDomain {
     api_domain,
     metadata,
+    stale: false,
+    age: 0,
   })
 }
 
-pub async fn do_dns_query_for_bns(domain_name: String, tld: String) -> QueryResult {
-  if let Ok(result) = bns_domain_api(domain_name, tld).await {
-    /*In order, look for:
-    - "A" record
-    - "CNAME" record
-    - "redirect" (Cname to self, redirect to specified)
-    - "resolved_address" (Cname to self, redirect to creeper)
-    */
-    if let Some(a_record) = result.metadata.get("A") {
-      if let Some(a) = parse_a_record(a_record) {
-        return QueryResult::A(a, None);
-      }
+pub async fn do_dns_query_for_bns(domain_name: String, tld: String) -> Result<QueryResult, ResolveError> {
+  let result = bns_domain_api(domain_name, tld).await?;
+  Ok(query_result_for(&result))
+}
+
+fn ttls_for(result: &BnsDomain) -> Ttls {
+  if result.stale {
+    Ttls { default: STALE_TTL, per_type: HashMap::new() }
+  } else {
+    Ttls::from_metadata(&result.metadata).aged(result.age)
+  }
+}
+
+pub fn query_result_for(result: &BnsDomain) -> QueryResult {
+  /*In order, look for:
+  - "A" and/or "AAAA" record
+  - "CNAME" record
+  - "redirect" (A to self, redirect to specified)
+  - "resolved_address" (A to self, redirect to creeper)
+  "TXT", "MX", "SRV", "SVCB" and "HTTPS" records are served alongside any of them, except CNAME
+  */
+  let ttls = ttls_for(result);
+  //invalid addresses are skipped, the rest are still served
+  let a: Vec<[u8; 4]> = result.metadata.get("A").map(|a_record| a_record.split().into_iter().filter_map(parse_a_record).collect()).unwrap_or_default();
+  let aaaa: Vec<[u8; 16]> = result.metadata.get("AAAA").map(|aaaa_record| aaaa_record.split().into_iter().filter_map(parse_aaaa_record).collect()).unwrap_or_default();
+  let (service_txt, txt): (Vec<TxtRecord>, Vec<TxtRecord>) = result.metadata.get("TXT").map(|txt_record| txt_record.list().into_iter().map(TxtRecord::parse).partition(|txt| !txt.service.is_empty())).unwrap_or_default();
+  let txt: Vec<String> = txt.into_iter().map(|txt| txt.text).collect();
+  let mx: Vec<MxRecord> = result.metadata.get("MX").map(|mx_record| mx_record.split().into_iter().filter_map(MxRecord::parse).collect()).unwrap_or_default();
+  let srv: Vec<SrvRecord> = result.metadata.get("SRV").map(|srv_record| srv_record.split().into_iter().filter_map(SrvRecord::parse).collect()).unwrap_or_default();
+  //alpn values have commas in them, so one record per list item
+  let svcb: Vec<SvcbData> = result.metadata.get("SVCB").map(|svcb_record| svcb_record.list().into_iter().filter_map(parse_svcb_record).collect()).unwrap_or_default();
+  let https: Vec<SvcbData> = result.metadata.get("HTTPS").map(|https_record| https_record.list().into_iter().filter_map(parse_svcb_record).collect()).unwrap_or_default();
+  let mut records = BnsRecords { a, aaaa, txt, service_txt, mx, srv, svcb, https, ttls: ttls.clone(), redirect: None, stale: result.stale };
+  if records.a.is_empty() && records.aaaa.is_empty() {
+    if let Some(cname_record) = result.metadata.get("CNAME").and_then(|value| value.as_str()) {
+      return QueryResult::Cname(cname_record.to_string(), ttls.get(TYPE_CNAME), result.stale);
     }
-    if let Some(cname_record) = result.metadata.get("CNAME") {
-      return QueryResult::Cname(cname_record.to_string());
+    if let Some(redirect) = result.metadata.get("redirect").and_then(|value| value.as_str()) {
+      records.a = vec![SELF_IP];
+      records.redirect = Some(redirect.to_string());
+    } else if let Some(ref resolved_address) = result.api_domain.resolved_address {
+      records.a = vec![SELF_IP];
+      records.redirect = Some(format!("https://creeper.banano.cc/account/{}", resolved_address));
     }
-    if let Some(redirect) = result.metadata.get("redirect") {
-      return QueryResult::A(SELF_IP, Some(redirect.to_string()));
+  }
+  //registered but nothing to serve is NODATA, not NXDOMAIN
+  QueryResult::Records(records)
+}
+
+//"ban", "BAN", "Ban", etc, since the client's case is passed through as is
+pub fn is_bns_tld(tld: &str) -> bool {
+  TLDS.iter().any(|bns_tld| bns_tld.eq_ignore_ascii_case(tld))
+}
+
+async fn do_internal_dns_query(host: &str) -> Result<QueryResult, ResolveError> {
+  let (domain_name, tld) = extract_tld(host);
+  if is_bns_tld(tld) {
+    if domain_name.is_empty() {
+      return Ok(QueryResult::Zone);
+    }
+    //_matrix._tcp.prussia.ban: look up prussia.ban, then only keep the SRV and TXT records for _matrix._tcp
+    let (service, domain_name) = split_service_labels(domain_name);
+    if service.eq_ignore_ascii_case(ADDRESS_SERVICE) {
+      let bns_domain = bns_address_domain(domain_name.to_string(), tld.to_string()).await?;
+      return Ok(match resolved_address(&bns_domain) {
+        Some(address) => QueryResult::Records(BnsRecords { txt: vec![address], ttls: ttls_for(&bns_domain), stale: bns_domain.stale, ..BnsRecords::default() }),
+        None => QueryResult::NXDomain,
+      });
+    }
+    let result = do_dns_query_for_bns(domain_name.to_string(), tld.to_string()).await?;
+    if service.is_empty() {
+      //SRV records (and TXT records with a service) only exist at their _service._proto name, not at the domain itself
+      return Ok(match result {
+        QueryResult::Records(records) => QueryResult::Records(BnsRecords { srv: Vec::new(), service_txt: Vec::new(), ..records }),
+        result => result,
+      });
     }
-    if let Some(resolved_address) = result.api_domain.resolved_address {
-      return QueryResult::A(SELF_IP, Some(format!("https://creeper.banano.cc/account/{}", resolved_address)));
+    if let QueryResult::Records(records) = result {
+      let srv: Vec<SrvRecord> = records.srv.into_iter().filter(|srv| srv.service.eq_ignore_ascii_case(service)).collect();
+      let txt: Vec<String> = records.service_txt.into_iter().filter(|txt| txt.service.eq_ignore_ascii_case(service)).map(|txt| txt.text).collect();
+      if !srv.is_empty() || !txt.is_empty() {
+        return Ok(QueryResult::Records(BnsRecords { txt, srv, ttls: records.ttls, stale: records.stale, ..BnsRecords::default() }));
+      }
     }
-    QueryResult::NXDomain
+    Ok(QueryResult::NXDomain)
   } else {
-    QueryResult::NXDomain
+    Ok(QueryResult::NonBns)
+  }
+}
+
+//the BNS TLD the host is under, eg: "ban" for "prussia.ban", "ban.k" for "prussia.ban.k"
+fn zone_of(host: &str) -> Name {
+  let (domain_name, _) = extract_tld(host);
+  let zone = if domain_name.is_empty() {
+    host
+  } else {
+    &host[(domain_name.len() + 1)..]
+  };
+  Name::from_dotted(zone).unwrap_or_default()
+}
+
+//also goes in the authority section for NXDOMAIN and NODATA, tells the client how long it can cache that there is nothing (rfc 2308 section 2.2)
+fn soa_record(host: &str) -> ResourceRecord {
+  let zone = zone_of(host);
+  let mut rname_labels = vec![b"hostmaster".to_vec()];
+  rname_labels.extend_from_slice(zone.labels());
+  ResourceRecord {
+    name: zone,
+    class: CLASS_IN,
+    ttl: NEGATIVE_TTL,
+    rdata: RData::Soa {
+      mname: Name::from_dotted(NAMESERVERS[0]).unwrap_or_default(),
+      rname: Name::from_labels(rname_labels),
+      serial: 1,
+      refresh: SOA_REFRESH,
+      retry: SOA_RETRY,
+      expire: SOA_EXPIRE,
+      minimum: NEGATIVE_TTL,
+    },
   }
 }
 
-async fn do_internal_dns_query(host: &str) -> QueryResult {
-  let (domain_name, tld) = extract_tld(&host);
-  if TLDS.contains(&tld) {
-    //todo: be better
-    do_dns_query_for_bns(domain_name.to_string(), tld.to_string()).await
+fn ns_records(host: &str) -> Vec<ResourceRecord> {
+  let zone = zone_of(host);
+  NAMESERVERS.iter().filter_map(|ns| Name::from_dotted(ns).ok()).map(|ns| ResourceRecord {
+    name: zone.clone(),
+    class: CLASS_IN,
+    ttl: NS_TTL,
+    rdata: RData::Ns(ns),
+  }).collect()
+}
+
+//start of a response to the query, with no answers yet
+//the id, opcode and RD are echoed, and the question is copied from the query as is (including case, type and class),
+//since stub resolvers check those match what they sent
+//we never set RA, indicating we do not support recursion, forcing the browser to resolve the cname
+//if the query has an OPT, so does the response (with our payload size and the DO bit echoed). if not, it can't (rfc 6891 section 7)
+//options from the query are not echoed, unknown ones are just ignored (rfc 6891 section 6.1.2)
+fn response_to(query: &Message, rcode: u16) -> Message {
+  let mut response = Message {
+    header: Header {
+      id: query.header.id,
+      qr: true,
+      opcode: query.header.opcode,
+      rd: query.header.rd,
+      ..Header::default()
+    },
+    questions: query.questions.clone(),
+    ..Message::default()
+  };
+  if let Some(edns) = query.edns() {
+    response.set_edns(Edns {
+      udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+      extended_rcode: 0,
+      version: EDNS_VERSION,
+      dnssec_ok: edns.dnssec_ok,
+      options: Vec::new(),
+    });
+  }
+  response.set_rcode(rcode);
+  response
+}
+
+//pads the response if the client padded the query (rfc 8467 section 4.1)
+fn finish_response(query: &Message, mut response: Message) -> Answer {
+  let padded = query.edns().is_some_and(|edns| edns.option(EDNS_OPTION_PADDING).is_some());
+  let bytes = if padded {
+    response.encode_padded(PADDING_BLOCK_SIZE)
   } else {
-    QueryResult::NonBns
+    response.encode()
+  };
+  Answer { bytes: Some(bytes) }
+}
+
+//how long ago the host's domain was cached as not resolving (0 if it wasn't)
+fn negative_cache_age(host: &str) -> u32 {
+  let (domain_name, tld) = extract_tld(host);
+  let (_, domain_name) = split_service_labels(domain_name);
+  let key = (domain_name.to_lowercase(), tld.to_lowercase());
+  BNS_NEGATIVE_CACHE.lock().unwrap().peek(&key).map_or(0, |(cached_at, _)| secs_since(*cached_at))
+}
+
+//the SOA makes it cacheable, we are the authority for the BNS TLDs
+//its TTL and MINIMUM are lowered by age, so an NXDOMAIN we cached isn't cached downstream for longer than NEGATIVE_TTL in total
+fn nxdomain_response(query: &Message, host: &str, age: u32) -> Message {
+  let mut response = response_to(query, RCODE_NXDOMAIN);
+  response.header.aa = true;
+  let mut soa = soa_record(host);
+  soa.ttl = soa.ttl.saturating_sub(age);
+  if let RData::Soa { ref mut minimum, .. } = soa.rdata {
+    *minimum = minimum.saturating_sub(age);
+  }
+  response.authorities.push(soa);
+  response
+}
+
+//rfc 8914, says why something failed. only if the client sent an OPT, otherwise there is nowhere to put it
+fn add_extended_error(response: &mut Message, (info_code, extra_text): (u16, &str)) {
+  if let Some(mut edns) = response.edns() {
+    let mut data = info_code.to_be_bytes().to_vec();
+    data.extend_from_slice(extra_text.as_bytes());
+    edns.options.push((EDNS_OPTION_EDE, data));
+    response.set_edns(edns);
+  }
+}
+
+//NXDOMAIN, SERVFAIL, etc: just the header and question, no answers
+fn error_answer(query: &Message, rcode: u16) -> Answer {
+  let response = response_to(query, rcode);
+  //need to send 200 even if nxdomain, see rfc8484 4.2.1
+  finish_response(query, response)
+}
+
+//one client for all the upstream queries, so the connection to NON_BNS_DOH gets reused instead of a new tls handshake every time
+static UPSTREAM_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+
+//(name lowercased, qtype, qclass, whether the query had an OPT, DO bit, CD bit), since the response depends on all of those
+//CD answers aren't validated by the upstream, so must never be served to clients that didn't set it (rfc 4035 section 4.7)
+type UpstreamCacheKey = (String, u16, u16, bool, bool, bool);
+
+//the response, and when it was cached (for decrementing the TTLs)
+static UPSTREAM_CACHE: LazyLock<Mutex<LruCache<UpstreamCacheKey, (Instant, Message)>>> = LazyLock::new(|| Mutex::new(LruCache::new(UPSTREAM_CACHE_CAPACITY)));
+
+pub fn upstream_cache_stats() -> CacheStats {
+  UPSTREAM_CACHE.lock().unwrap().stats()
+}
+
+fn upstream_cache_key(query: &Message) -> UpstreamCacheKey {
+  let question = &query.questions[0];
+  let edns = query.edns();
+  (question.name.to_string().to_lowercase(), question.qtype, question.qclass, edns.is_some(), edns.is_some_and(|edns| edns.dnssec_ok), query.header.z & 0b001 != 0)
+}
+
+//how long an upstream response can be cached for, None if it shouldn't be (errors, truncated, TTL of 0)
+//negative answers (NXDOMAIN and NODATA) are cached for the SOA minimum, and can't be without an SOA (rfc 2308 section 5)
+fn upstream_cache_ttl(response: &Message) -> Option<u32> {
+  let rcode = response.rcode();
+  if response.header.tc || (rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN) {
+    return None;
+  }
+  let records = || response.answers.iter().chain(&response.authorities).chain(&response.additionals).filter(|record| record.rtype() != TYPE_OPT);
+  let mut ttl = records().map(|record| record.ttl).min()?;
+  if rcode == RCODE_NXDOMAIN || response.answers.is_empty() {
+    let soa_minimum = response.authorities.iter().find_map(|record| match record.rdata {
+      RData::Soa { minimum, .. } => Some(minimum),
+      _ => None,
+    })?;
+    ttl = ttl.min(soa_minimum);
+  }
+  if ttl == 0 {
+    return None;
+  }
+  Some(ttl.min(MAX_TTL))
+}
+
+fn cache_upstream_response(query: &Message, bytes: &[u8]) {
+  let Ok(mut response) = Message::decode(bytes) else {
+    return;
+  };
+  let Some(ttl) = upstream_cache_ttl(&response) else {
+    return;
+  };
+  //padding is per response, finish_response adds it back if the client wants it
+  if let Some(mut edns) = response.edns() {
+    edns.options.retain(|(code, _)| *code != EDNS_OPTION_PADDING);
+    response.set_edns(edns);
   }
+  UPSTREAM_CACHE.lock().unwrap().insert(upstream_cache_key(query), (Instant::now(), response), Some(Duration::from_secs(ttl.into())));
+}
+
+//the cached response, with TTLs lowered by how long it has been cached, and the client's id, RD and question (with its case)
+fn cached_upstream_answer(query: &Message) -> Option<Answer> {
+  let (cached_at, mut response) = UPSTREAM_CACHE.lock().unwrap().get(&upstream_cache_key(query))?;
+  let elapsed = secs_since(cached_at);
+  for record in response.answers.iter_mut().chain(&mut response.authorities).chain(&mut response.additionals) {
+    if record.rtype() != TYPE_OPT {
+      record.ttl = record.ttl.saturating_sub(elapsed);
+    }
+  }
+  response.header.id = query.header.id;
+  response.header.rd = query.header.rd;
+  response.questions = query.questions.clone();
+  Some(finish_response(query, response))
+}
+
+//REFUSED for a query that would be forwarded to NON_BNS_DOH, for listeners that shouldn't forward (see UDP_FORWARD_NON_BNS)
+//None if it is for a BNS domain (or doesn't parse), so it should be answered as usual
+pub fn refuse_non_bns_query(dns_query: &[u8]) -> Option<Vec<u8>> {
+  let query = Message::decode(dns_query).ok()?;
+  let question = query.questions.first()?;
+  let host = question.name.to_string();
+  let (_, tld) = extract_tld(&host);
+  if query.header.qr || query.questions.len() != 1 || is_bns_tld(tld) {
+    return None;
+  }
+  let mut response = response_to(&query, RCODE_REFUSED);
+  add_extended_error(&mut response, (EDE_PROHIBITED, "only BNS domains are resolved over UDP"));
+  finish_response(&query, response).bytes
 }
 
 pub async fn answer_dns_query(dns_query: Vec<u8>, nested: usize) -> Answer {
   if nested > 2 {
     return Answer { bytes: None };
   }
-  let client = Client::new();
-  //Identification (not needed for DoH, should be 0), 16 bits
-  /*
-  Flags (total 16 bits)
-  QR (query: 0, reply: 1), 1 bit
-  OPCODE (standard: 0, inverse: 1, status: 2), 4 bits (opcode in query is repeated in response)
-  AA (if authorative answer for hostname), 1 bit
-  TC (whether message was truncated), 1 bit
-  RD (where recursion desired), 1 bit
-  RA (in response, whether recursion available), 1 bit
-  Z (reserved), 3 bits
-  RCODE (response code, NOERROR: 0, FORM(at)ERR: 1, SERVFAIL: 2, NXDOMAIN: 3), 4 bits
-  */
-  //# of questions, 16 bits
-  //# of answers (response), 16 bits
-  //# of authority resource records (aka RR), 16 bits
-  //# of additional RRs, 16 bits
-  //so above is 12 bytes
-
-  //what the first 12 bytes will be for a regular response (4th [0 -> 3] and 8th [1 -> 0] byte will be different for NXDOMAIN, similar for SERVFAIL)
-  //Id: 0, 0. Flags: 1 0000 0 0 0, 0 000 0000 (or 0011 for NXDOMAIN, 0010 for SERVFAIL). # of q: 0, 1 (question in query apparently needs to be copied to response). # of a: 0 1 (0 if NXDOMAIN, SERVFAIL). # of aurr: 0 0. # of adrr: 0 0.
-  //ok so above is 128 0 but everyone seems to be doing 129 128 (see flags above)
-  //we do 129 0, indicating we do not support recursion, forcing the browser to resolve the cname
-  let mut resp_start_bytes: Vec<u8> = vec![0, 0, 129, 0, 0, 1, 0, 1, 0, 0, 0, 0];
-  //let mut dns_query: Vec<u8> = vec![0, 0, 1, 128, 0, 1, 0, 0, 0, 0, 0, 0];
-  /*
-  Question section
-  Can have many questions, but in practice most only allow 1. so we will do same, plus its easier (see "# of questions" field)
-  IMPORTANT: the question in the query is apparently COPIED TO THE RESPONSE too, so response's QDCOUNT will be 1 and contain the question
-  Format:
-  NAME, variable length
-  - NAME is divided into multiple zones/labels (eg: en, wikipedia, org), each zone has 8 bits indicating how many bytes the length is (eg, en is 2 bytes), then the name in ascii, repeated), 0 indicates the NAME is done. Eg: 2 en 9 wikipedia 3 org 0
-  - labels start with 00
-  - labels start with length of label
-  - NAME or a zone can also be a pointer (16 bits) if first two bits are 11, other 14 bits are an offset
-  TYPE (of question. A, AAAA, MX, TXT, or special from 251-255, 255 is * [all records]), 16 bits
-  CLASS (probably IN for internet), 16 bits
-  */
-  /*
-  Answer section
-  Can have many answer, but in our case only one (see "# of answers" field)
-  RR Format:
-  NAME, variable length (see above in question section)
-  TYPE (A, AAAA, MX, TXT, etc), 16 bits
-  CLASS, 16 bits
-  TTL (time record is valid for), 32 bits
-  RDLENGTH (length of RDATA in bytes), 16 bits
-  RDATA (additional RR specific data, see RDLENGTH), variable length
-  */
-  //Authority section: RRs that point toward authority (NOT RELEVANT for us)
-  //Additional space section: RRs with additional information (NOT RELEVANT for us)
-  //first do some sanity checks, extract ID (should be 0 but w/e), make sure is query
+  //first do some sanity checks, make sure it parses
   //also only accept if one question (pretty sure no one does multiple nowadays anyways)
-  //also check length
-  //TODO
-  //
-  //println!("q: {:?}", dns_query);
-  //extract the host name
-  let query_host_wrapped = extract_host_from_dns_query(&dns_query, None);
-  if let Ok(query_host) = query_host_wrapped {
-    println!("\nRequested: {}\n", query_host);
-    println!("q {} {:?}", query_host, dns_query);
-    //now actual dns query stuff, and http response
-    match do_internal_dns_query(&query_host).await {
-      QueryResult::Cname(cname) => {
-        //cname
-        //firefox, at least, never asks directly for cname, so we return as additional record?
-        let mut host_label_bytes = query_hostname_to_label_bytes(&query_host);
-        let qtype = dns_query[12 + host_label_bytes.len() + 1];
-        //append question to resp_start_bytes (yes, this is a response, but question needs to be copied from query, apparently)
-        //label
-        resp_start_bytes.append(&mut host_label_bytes);
-        //type and class are whatever the qtype is and IN (1)
-        resp_start_bytes.extend_from_slice(&[0 as u8, qtype]);
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        //append answer to resp_start_bytes
-        //offset to the label in the front
-        resp_start_bytes.extend_from_slice(&[192 as u8, 12 as u8]);
-        //resp_start_bytes.append(&mut query_hostname_to_label_bytes(&query_host));
-        //type and class are CNAME (5) and IN (1)
-        resp_start_bytes.extend_from_slice(&[0 as u8, 5 as u8]);
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        //TTL (arbitrarily pick 10 minutes, or 600 seconds [0x0258])
-        resp_start_bytes.extend_from_slice(&[0 as u8, 0 as u8, 2 as u8, 88 as u8]);
-        let label_bytes = query_hostname_to_label_bytes(&cname);
-        //RD LENGTH is two bytes
-        resp_start_bytes.push(0);
-        resp_start_bytes.push(label_bytes.len().try_into().unwrap());
-        //RDDATA
-        resp_start_bytes.extend_from_slice(&label_bytes);
-        //recursively resolve CNAME, no longer needed since we tell the client we don't recurse
-        /*if qtype == 5 {
-          //question is 1, answer is 0
-          let mut question: Vec<u8> = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
-          question.extend(query_hostname_to_label_bytes(&cname));
-          question.extend([0, 1, 0, 1]); //A (1) and IN (1)
-          if let Some(nested_rb) = &Box::pin(answer_dns_query(dns_query, nested + 1)).await.bytes {
-            let ans = resp_start_bytes[7];
-            resp_start_bytes[7] += ans;
-            let mut ni = nested_rb.iter();
-            for _ in 0..ans {
-              //
-            }
-          }
-        }*/
-        Answer { bytes: Some(resp_start_bytes) }
-      },
-      QueryResult::A(ip, _) => {
-        //TODO: make sure question type is all records (255) or A (1) (unrelated, CNAME is 5),
-        //extract_type_bytes_from_dns_query?
-        //
-        //construct response
-        //append question to resp_start_bytes (yes, this is a response, but question needs to be copied from query, apparently)
-        //label
-        resp_start_bytes.append(&mut query_hostname_to_label_bytes(&query_host));
-        //type and class are A (1) and IN (1)
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        //append answer to resp_start_bytes
-        //offset to the label in the front
-        resp_start_bytes.extend_from_slice(&[192 as u8, 12 as u8]);
-        //resp_start_bytes.append(&mut query_hostname_to_label_bytes(&query_host));
-        //type and class are A (1) and IN (1)
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        resp_start_bytes.extend_from_slice(&[0 as u8, 1 as u8]);
-        //TTL (arbitrarily pick 10 minutes, or 600 seconds [0x0258])
-        resp_start_bytes.extend_from_slice(&[0 as u8, 0 as u8, 2 as u8, 88 as u8]);
-        //RD LENGTH is two bytes, A record is 4 bytes
-        resp_start_bytes.push(0);
-        resp_start_bytes.push(4);
-        //RDDATA
-        resp_start_bytes.extend_from_slice(&ip);
-        Answer { bytes: Some(resp_start_bytes) }
-      },
-      QueryResult::NXDomain => {
-        //Not found
-        resp_start_bytes[5] = 0; //# of q: 0
-        //explanation of below, see above where resp_start_bytes is defined
-        resp_start_bytes[2] = 3;
-        resp_start_bytes[7] = 0;
-        //need to send 200 even if nxdomain, see rfc8484 4.2.1
-        Answer { bytes: Some(resp_start_bytes) }
-      },
-      QueryResult::NonBns => {
-        //regular domain, ens or handshake domain
-        //hnsdns handles all, how nice. No adblock though, like mullvad...
-        //forward query to other DoH, and return what it returns
-        let mut header_map = HeaderMap::new();
-        header_map.insert(ACCEPT, "application/dns-message".parse().unwrap());
-        header_map.insert(CONTENT_TYPE, "application/dns-message".parse().unwrap());
-        let try_res = client.post(NON_BNS_DOH).body(dns_query).headers(header_map).send().await; //in the future, throw 500 if fails
-        if let Ok(res) = try_res {
-          //let res_status = res.status().as_u16(); //todo: status should be 200
-          //println!("response from hnsdns: {:?}", res.bytes().unwrap().to_vec());
-          let rb = res.bytes().await.unwrap().to_vec();
-          Answer { bytes: Some(rb) }
-        } else {
-          println!("SERVFAIL");
-          resp_start_bytes[5] = 0; //# of q: 0
-          //should it be a SERVFAIL dns reply?
-          resp_start_bytes[2] = 2;
-          resp_start_bytes[7] = 0;
-          //need to send 200 even if nxdomain, see rfc8484 4.2.1
-          Answer { bytes: Some(resp_start_bytes) }
-        }
-      },
-    }
-  } else {
+  //responses (QR set) are dropped, answering them could start a loop with whoever (supposedly) sent them
+  let query = match Message::decode(&dns_query) {
+    Ok(query) if query.questions.len() == 1 && !query.header.qr => query,
     //400 bad request, since could not find host in question section of query
-    Answer { bytes: None }
+    _ => return Answer { bytes: None },
+  };
+  if query.additionals.iter().filter(|record| record.rtype() == TYPE_OPT).count() > 1 {
+    //only one OPT allowed (rfc 6891 section 6.1.1)
+    return error_answer(&query, RCODE_FORMERR);
+  }
+  if query.edns().is_some_and(|edns| edns.version > EDNS_VERSION) {
+    //we only speak EDNS version 0 (rfc 6891 section 6.1.3)
+    return error_answer(&query, RCODE_BADVERS);
+  }
+  if query.header.opcode != OPCODE_QUERY {
+    //inverse queries, status, notify, update, etc
+    return error_answer(&query, RCODE_NOTIMP);
+  }
+  let question = &query.questions[0];
+  let query_host = question.name.to_string();
+  println!("\nRequested: {}\n", query_host);
+  println!("q {} {:?}", query_host, dns_query);
+  //now actual dns query stuff, and http response
+  let result = match do_internal_dns_query(&query_host).await {
+    Ok(result) => result,
+    Err(error) => {
+      println!("BNS resolution failed: {:?}", error);
+      let mut response = if error.rcode() == RCODE_NXDOMAIN {
+        nxdomain_response(&query, &query_host, negative_cache_age(&query_host))
+      } else {
+        response_to(&query, error.rcode())
+      };
+      add_extended_error(&mut response, error.extended_error());
+      return finish_response(&query, response);
+    },
+  };
+  match result {
+    QueryResult::Cname(cname, ttl, stale) => {
+      //a CNAME means there can't be any other records for the name, so it is the answer whatever the qtype is
+      //firefox, at least, never asks directly for cname
+      let Ok(target) = Name::from_dotted(&cname) else {
+        //the CNAME in the metadata is not a valid hostname
+        return error_answer(&query, RCODE_SERVFAIL);
+      };
+      let mut response = response_to(&query, RCODE_NOERROR);
+      response.header.aa = true;
+      response.answers.push(ResourceRecord { name: question.name.clone(), class: CLASS_IN, ttl, rdata: RData::Cname(target) });
+      if stale {
+        add_extended_error(&mut response, STALE_EXTENDED_ERROR);
+      }
+      finish_response(&query, response)
+    },
+    QueryResult::Records(records) => {
+      let mut response = response_to(&query, RCODE_NOERROR);
+      response.header.aa = true;
+      let wanted = |rtype: u16| question.qtype == rtype || question.qtype == TYPE_ANY;
+      let mut rdatas: Vec<RData> = Vec::new();
+      //one RR per address
+      if wanted(TYPE_A) {
+        rdatas.extend(records.a.into_iter().map(|a| RData::A(Ipv4Addr::from(a))));
+      }
+      if wanted(TYPE_AAAA) {
+        rdatas.extend(records.aaaa.into_iter().map(|aaaa| RData::Aaaa(Ipv6Addr::from(aaaa))));
+      }
+      if wanted(TYPE_TXT) {
+        rdatas.extend(records.txt.iter().map(|txt| RData::Txt(split_txt_record(txt))));
+      }
+      if wanted(TYPE_MX) {
+        rdatas.extend(records.mx.into_iter().map(|mx| RData::Mx { preference: mx.preference, exchange: mx.exchange }));
+      }
+      if wanted(TYPE_SRV) {
+        rdatas.extend(records.srv.into_iter().map(|srv| RData::Srv { priority: srv.priority, weight: srv.weight, port: srv.port, target: srv.target }));
+      }
+      if wanted(TYPE_SVCB) {
+        rdatas.extend(records.svcb.into_iter().map(RData::Svcb));
+      }
+      if wanted(TYPE_HTTPS) {
+        rdatas.extend(records.https.into_iter().map(RData::Https));
+      }
+      for rdata in rdatas {
+        response.answers.push(ResourceRecord { name: question.name.clone(), class: CLASS_IN, ttl: records.ttls.get(rdata.rtype()), rdata });
+      }
+      if response.answers.is_empty() {
+        //MX, HTTPS, AAAA when there is only an A, etc: the name exists but has nothing of that type (NODATA), so no answers, just the SOA
+        response.authorities.push(soa_record(&query_host));
+      }
+      if records.stale {
+        add_extended_error(&mut response, STALE_EXTENDED_ERROR);
+      }
+      finish_response(&query, response)
+    },
+    QueryResult::Zone => {
+      let mut response = response_to(&query, RCODE_NOERROR);
+      response.header.aa = true;
+      if question.qtype == TYPE_SOA || question.qtype == TYPE_ANY {
+        response.answers.push(soa_record(&query_host));
+      }
+      if question.qtype == TYPE_NS || question.qtype == TYPE_ANY {
+        response.answers.extend(ns_records(&query_host));
+      }
+      if response.answers.is_empty() {
+        response.authorities.push(soa_record(&query_host));
+      }
+      finish_response(&query, response)
+    },
+    QueryResult::NXDomain => {
+      //Not found
+      finish_response(&query, nxdomain_response(&query, &query_host, 0))
+    },
+    QueryResult::NonBns => {
+      //regular domain, ens or handshake domain
+      //hnsdns handles all, how nice. No adblock though, like mullvad...
+      //forward query to other DoH, and return what it returns
+      if let Some(answer) = cached_upstream_answer(&query) {
+        return answer;
+      }
+      let mut header_map = HeaderMap::new();
+      header_map.insert(ACCEPT, "application/dns-message".parse().unwrap());
+      header_map.insert(CONTENT_TYPE, "application/dns-message".parse().unwrap());
+      let try_res = UPSTREAM_CLIENT.post(NON_BNS_DOH).body(dns_query).headers(header_map).send().await;
+      let try_rb = match try_res {
+        Ok(res) if res.status().is_success() => res.bytes().await.ok(),
+        _ => None,
+      };
+      if let Some(rb) = try_rb {
+        //println!("response from hnsdns: {:?}", rb);
+        cache_upstream_response(&query, &rb);
+        Answer { bytes: Some(rb.to_vec()) }
+      } else {
+        println!("SERVFAIL");
+        let mut response = response_to(&query, RCODE_SERVFAIL);
+        add_extended_error(&mut response, (EDE_NETWORK_ERROR, "upstream DoH unreachable"));
+        finish_response(&query, response)
+      }
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::message::Question;
+
+  fn query(host: &str, qtype: u16) -> Vec<u8> {
+    Message {
+      header: Header { id: 0x1234, rd: true, ..Header::default() },
+      questions: vec![Question { name: Name::from_dotted(host).unwrap(), qtype, qclass: CLASS_IN }],
+      ..Message::default()
+    }.encode()
+  }
+
+  #[test]
+  fn mixed_case_tld_is_bns() {
+    assert!(is_bns_tld("BaN"));
+    assert!(!is_bns_tld("com"));
+    assert_eq!(refuse_non_bns_query(&query("PrUsSiA.BaN", TYPE_A)), None);
+    assert!(refuse_non_bns_query(&query("example.com", TYPE_A)).is_some());
+  }
+
+  #[test]
+  fn upstream_cache_key_has_cd() {
+    let mut query = Message::decode(&query("example.com", TYPE_A)).unwrap();
+    let key = upstream_cache_key(&query);
+    query.header.z |= 0b001;
+    assert_ne!(upstream_cache_key(&query), key);
+  }
+
+  #[rocket::async_test]
+  async fn mixed_case_zone_is_answered() {
+    let response = Message::decode(&answer_dns_query(query("BaN", TYPE_SOA), 0).await.bytes.unwrap()).unwrap();
+    assert_eq!(response.rcode(), RCODE_NOERROR);
+    assert!(response.header.aa);
+    assert_eq!(response.answers[0].rtype(), TYPE_SOA);
+    assert_eq!(response.questions[0].name.to_string(), "BaN");
   }
 }
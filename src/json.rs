@@ -0,0 +1,169 @@
+use std::io::Cursor;
+
+use rocket::Response;
+use rocket::response;
+use rocket::response::Responder;
+use rocket::http::Status;
+use rocket::request::Request;
+
+use serde::Serialize;
+
+use crate::dns::{ answer_dns_query, cache_max_age, EDNS_UDP_PAYLOAD_SIZE, EDNS_VERSION };
+use crate::message::{ Edns, Header, Message, Name, Question, RData, ResourceRecord, CLASS_IN, EDNS_OPTION_EDE, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_CNAME, TYPE_HTTPS, TYPE_MX, TYPE_NS, TYPE_OPT, TYPE_PTR, TYPE_SOA, TYPE_SRV, TYPE_SVCB, TYPE_TXT };
+use crate::utils::{ escape_character_string, svcb_record_to_string };
+
+//the json api google and cloudflare have (https://developers.google.com/speed/public-dns/docs/doh/json)
+//it is just another way of writing the same query, so it gets turned into a dns message and answered like any other
+
+const TYPE_NAMES: [(&str, u16); 13] = [("A", TYPE_A), ("NS", TYPE_NS), ("CNAME", TYPE_CNAME), ("SOA", TYPE_SOA), ("PTR", TYPE_PTR), ("MX", TYPE_MX), ("TXT", TYPE_TXT), ("AAAA", TYPE_AAAA), ("SRV", TYPE_SRV), ("SVCB", TYPE_SVCB), ("HTTPS", TYPE_HTTPS), ("ANY", TYPE_ANY), ("*", TYPE_ANY)];
+
+#[derive(Serialize)]
+struct JsonQuestion {
+  name: String,
+  #[serde(rename = "type")]
+  qtype: u16,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+  name: String,
+  #[serde(rename = "type")]
+  rtype: u16,
+  #[serde(rename = "TTL")]
+  ttl: u32,
+  data: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+  status: u16,
+  #[serde(rename = "TC")]
+  tc: bool,
+  #[serde(rename = "RD")]
+  rd: bool,
+  #[serde(rename = "RA")]
+  ra: bool,
+  #[serde(rename = "AD")]
+  ad: bool,
+  #[serde(rename = "CD")]
+  cd: bool,
+  question: Vec<JsonQuestion>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  answer: Vec<JsonRecord>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  authority: Vec<JsonRecord>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  additional: Vec<JsonRecord>,
+  //the extended dns error text, if there is one
+  #[serde(skip_serializing_if = "Option::is_none")]
+  comment: Option<String>,
+}
+
+pub struct JsonAnswer {
+  pub json: Option<String>,
+  pub max_age: Option<u32>,
+}
+
+impl<'r> Responder<'r, 'static> for JsonAnswer {
+  fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+    if let Some(json) = self.json {
+      let mut response = Response::build();
+      if let Some(max_age) = self.max_age {
+        response.raw_header("Cache-Control", format!("max-age={}", max_age));
+      }
+      response.status(Status { code: 200 }).raw_header("Content-Type", "application/dns-json").sized_body(json.len(), Cursor::new(json)).ok()
+    } else {
+      Response::build().status(Status { code: 400 }).ok()
+    }
+  }
+}
+
+//"AAAA", "aaaa" or "28"
+fn parse_type(qtype: &str) -> Option<u16> {
+  TYPE_NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(qtype)).map(|(_, rtype)| *rtype).or_else(|| qtype.parse::<u16>().ok())
+}
+
+fn absolute(name: &Name) -> String {
+  format!("{}.", name)
+}
+
+//the zone file presentation format of the rdata, which is what the json api puts in "data"
+fn rdata_to_string(rdata: &RData) -> String {
+  match rdata {
+    RData::A(ip) => ip.to_string(),
+    RData::Aaaa(ip) => ip.to_string(),
+    RData::Ns(name) | RData::Cname(name) | RData::Ptr(name) => absolute(name),
+    RData::Soa { mname, rname, serial, refresh, retry, expire, minimum } => format!("{} {} {} {} {} {} {}", absolute(mname), absolute(rname), serial, refresh, retry, expire, minimum),
+    RData::Mx { preference, exchange } => format!("{} {}", preference, absolute(exchange)),
+    RData::Txt(strings) => strings.iter().map(|string| format!("\"{}\"", escape_character_string(string))).collect::<Vec<String>>().join(" "),
+    RData::Srv { priority, weight, port, target } => format!("{} {} {} {}", priority, weight, port, absolute(target)),
+    RData::Svcb(svcb) | RData::Https(svcb) => svcb_record_to_string(svcb),
+    //never shown, see json_records
+    RData::Opt(_) => String::new(),
+    //rfc 3597 section 5, the generic format for types we don't know
+    RData::Other(_, data) => {
+      let hex = data.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+      format!("\\# {} {}", data.len(), hex).trim_end().to_string()
+    },
+  }
+}
+
+//the OPT isn't a real record, so it is left out
+fn json_records(records: &[ResourceRecord]) -> Vec<JsonRecord> {
+  records.iter().filter(|record| record.rtype() != TYPE_OPT).map(|record| JsonRecord {
+    name: absolute(&record.name),
+    rtype: record.rtype(),
+    ttl: record.ttl,
+    data: rdata_to_string(&record.rdata),
+  }).collect()
+}
+
+pub async fn answer_json_query(name: &str, qtype: Option<&str>) -> JsonAnswer {
+  let bad_request = JsonAnswer { json: None, max_age: None };
+  let Ok(name) = Name::from_dotted(name) else {
+    return bad_request;
+  };
+  //A if no type is given, same as google
+  let Some(qtype) = qtype.map_or(Some(TYPE_A), parse_type) else {
+    return bad_request;
+  };
+  let mut query = Message {
+    header: Header { rd: true, ..Header::default() },
+    questions: vec![Question { name, qtype, qclass: CLASS_IN }],
+    ..Message::default()
+  };
+  //with an OPT, so failures come back with an extended dns error we can put in the comment
+  query.set_edns(Edns {
+    udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+    extended_rcode: 0,
+    version: EDNS_VERSION,
+    dnssec_ok: false,
+    options: Vec::new(),
+  });
+  let Some(bytes) = answer_dns_query(query.encode(), 0).await.bytes else {
+    return bad_request;
+  };
+  let Ok(response) = Message::decode(&bytes) else {
+    return bad_request;
+  };
+  let comment = response.edns().and_then(|edns| edns.option(EDNS_OPTION_EDE).map(|ede| String::from_utf8_lossy(ede.get(2..).unwrap_or_default()).to_string())).filter(|text| !text.is_empty());
+  let json_response = JsonResponse {
+    status: response.rcode(),
+    tc: response.header.tc,
+    rd: response.header.rd,
+    ra: response.header.ra,
+    //the two lower bits of Z (rfc 4035 section 3.2)
+    ad: response.header.z & 0b010 != 0,
+    cd: response.header.z & 0b001 != 0,
+    question: response.questions.iter().map(|question| JsonQuestion { name: absolute(&question.name), qtype: question.qtype }).collect(),
+    answer: json_records(&response.answers),
+    authority: json_records(&response.authorities),
+    additional: json_records(&response.additionals),
+    comment,
+  };
+  JsonAnswer {
+    json: serde_json::to_string(&json_response).ok(),
+    max_age: cache_max_age(&bytes),
+  }
+}
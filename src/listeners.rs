@@ -0,0 +1,256 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::config::TlsConfig;
+
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::net::{ TcpListener, UdpSocket };
+use tokio::sync::{ mpsc, Semaphore };
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{ Certificate, PrivateKey, ServerConfig };
+
+use crate::dns::{ answer_dns_query, refuse_non_bns_query, EDNS_UDP_PAYLOAD_SIZE };
+use crate::message::{ Message, RCODE_FORMERR, TYPE_OPT };
+
+//plain dns, for everything that can't do DoH (routers, dig, etc). needs root to bind, same as 443
+const UDP_ADDRESS: &str = "0.0.0.0:53";
+//whether non-BNS queries over udp get forwarded to NON_BNS_DOH like they are over DoH, otherwise they get REFUSED
+//off by default: udp source addresses can be spoofed, so with port 53 reachable from the internet it would be an open reflector
+//only turn it on if port 53 is firewalled to your own network. tcp and tls need a handshake, so always forward
+const UDP_FORWARD_NON_BNS: bool = false;
+//rfc 1035 section 4.2.1, the limit for clients that don't send an OPT
+const UDP_MIN_PAYLOAD_SIZE: usize = 512;
+//biggest possible udp datagram
+const UDP_MAX_PACKET_SIZE: usize = 65535;
+//for truncated answers and anything too big for udp (rfc 7766)
+const TCP_ADDRESS: &str = "0.0.0.0:53";
+//rfc 7766 section 6.2.3, close connections that go quiet so they don't pile up
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+//queries one connection can have being resolved at once, past this we stop reading from it until some are answered
+//otherwise one client could pipeline thousands of uncached names and have them all hit the BNS api at once
+const TCP_MAX_IN_FLIGHT: usize = 16;
+//open connections per listener (tcp and tls each), past this new ones wait in the accept backlog
+const TCP_MAX_CONNECTIONS: usize = 512;
+//DoT, for android's private dns, systemd-resolved, etc (rfc 7858). uses the same cert as the DoH server
+const TLS_ADDRESS: &str = "0.0.0.0:853";
+
+//the most we can send back to whoever sent the query
+//the client's advertised EDNS payload size, but never more than what we advertise (we don't want fragmentation either)
+fn udp_max_size(query: &[u8]) -> usize {
+  match Message::decode(query).ok().and_then(|query| query.edns()) {
+    Some(edns) => usize::from(edns.udp_payload_size.min(EDNS_UDP_PAYLOAD_SIZE)).max(UDP_MIN_PAYLOAD_SIZE),
+    None => UDP_MIN_PAYLOAD_SIZE,
+  }
+}
+
+//if the response doesn't fit, set TC and drop all the records (except the OPT) so the client retries over tcp
+//rfc 2181 section 9 says not to send partial RRsets, so just send none
+fn truncate(response: Vec<u8>, max_size: usize) -> Vec<u8> {
+  if response.len() <= max_size {
+    return response;
+  }
+  let Ok(mut message) = Message::decode(&response) else {
+    //should never happen, we encoded it. at least tell them to retry
+    let mut header = response[..12].to_vec();
+    header[2] |= 0b10;
+    header[4..12].fill(0);
+    return header;
+  };
+  message.header.tc = true;
+  message.answers.clear();
+  message.authorities.clear();
+  message.additionals.retain(|record| record.rtype() == TYPE_OPT);
+  message.encode()
+}
+
+//the DoH routes 400 on a query that doesn't parse, but over udp we can only send a FORMERR (rfc 1035 section 4.1.1)
+//if there isn't even a header, or it is a response (qr set), ignore it, otherwise we might end up in a loop with another server
+fn formerr(query: &[u8]) -> Option<Vec<u8>> {
+  if query.len() < 12 || query[2] & 0b1000_0000 != 0 {
+    return None;
+  }
+  let mut header = vec![0; 12];
+  header[..2].copy_from_slice(&query[..2]);
+  //QR, and the opcode and RD echoed
+  header[2] = 0b1000_0000 | (query[2] & 0b0111_1001);
+  header[3] = RCODE_FORMERR as u8;
+  Some(header)
+}
+
+async fn answer_query(query: Vec<u8>) -> Option<Vec<u8>> {
+  match answer_dns_query(query.clone(), 0).await.bytes {
+    Some(response) => Some(response),
+    None => formerr(&query),
+  }
+}
+
+async fn answer_udp_query(query: Vec<u8>) -> Option<Vec<u8>> {
+  let max_size = udp_max_size(&query);
+  if !UDP_FORWARD_NON_BNS && let Some(refused) = refuse_non_bns_query(&query) {
+    return Some(truncate(refused, max_size));
+  }
+  answer_query(query).await.map(|response| truncate(response, max_size))
+}
+
+//one task per query, so a slow BNS api lookup doesn't hold up everything else
+pub async fn serve_udp() {
+  let socket = match UdpSocket::bind(UDP_ADDRESS).await {
+    Ok(socket) => Arc::new(socket),
+    Err(e) => {
+      //keep serving DoH even if we can't get the port
+      println!("Could not bind DNS over UDP to {}: {}", UDP_ADDRESS, e);
+      return;
+    },
+  };
+  println!("DNS over UDP listening on {}", UDP_ADDRESS);
+  let mut buf = vec![0; UDP_MAX_PACKET_SIZE];
+  loop {
+    let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+      continue;
+    };
+    let query = buf[..len].to_vec();
+    let socket = Arc::clone(&socket);
+    tokio::spawn(async move {
+      if let Some(response) = answer_udp_query(query).await {
+        let _ = socket.send_to(&response, peer).await;
+      }
+    });
+  }
+}
+
+//rfc 7766 section 8, every message is prefixed with its length as 2 bytes
+async fn read_framed<R: AsyncRead + Unpin>(reader: &mut R) -> Option<Vec<u8>> {
+  let len = reader.read_u16().await.ok()?;
+  let mut message = vec![0; usize::from(len)];
+  reader.read_exact(&mut message).await.ok()?;
+  Some(message)
+}
+
+//one connection, over tcp (or anything else that uses the tcp framing)
+//queries are read while earlier ones are still being resolved (pipelining, rfc 7766 section 6.2.1.1), up to TCP_MAX_IN_FLIGHT,
+//and each response is written as soon as it is ready, in whatever order that is (rfc 7766 section 7)
+pub async fn serve_stream<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) {
+  let (mut reader, mut writer) = tokio::io::split(stream);
+  //bounded too, so a client that doesn't read its responses also stops us reading its queries
+  let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(TCP_MAX_IN_FLIGHT);
+  let writing = tokio::spawn(async move {
+    while let Some(response) = receiver.recv().await {
+      //can't be framed, should never happen
+      let Ok(len) = u16::try_from(response.len()) else {
+        continue;
+      };
+      let mut framed = len.to_be_bytes().to_vec();
+      framed.extend_from_slice(&response);
+      if writer.write_all(&framed).await.is_err() {
+        return;
+      }
+    }
+    let _ = writer.shutdown().await;
+  });
+  let in_flight = Arc::new(Semaphore::new(TCP_MAX_IN_FLIGHT));
+  //stops on EOF, a read error, or the idle timeout. queries already read still get their responses
+  loop {
+    //the permit is held until the response is handed to the writer
+    let Ok(permit) = Arc::clone(&in_flight).acquire_owned().await else {
+      break;
+    };
+    let Ok(Some(query)) = timeout(TCP_IDLE_TIMEOUT, read_framed(&mut reader)).await else {
+      break;
+    };
+    let sender = sender.clone();
+    tokio::spawn(async move {
+      if let Some(response) = answer_query(query).await {
+        let _ = sender.send(response).await;
+      }
+      drop(permit);
+    });
+  }
+  //the writer finishes once every in flight query has sent (and dropped) its sender
+  drop(sender);
+  let _ = writing.await;
+}
+
+pub async fn serve_tcp() {
+  let listener = match TcpListener::bind(TCP_ADDRESS).await {
+    Ok(listener) => listener,
+    Err(e) => {
+      println!("Could not bind DNS over TCP to {}: {}", TCP_ADDRESS, e);
+      return;
+    },
+  };
+  println!("DNS over TCP listening on {}", TCP_ADDRESS);
+  let connections = Arc::new(Semaphore::new(TCP_MAX_CONNECTIONS));
+  loop {
+    //wait for a free slot before accepting, never fails since the semaphore is never closed
+    let Ok(permit) = Arc::clone(&connections).acquire_owned().await else {
+      return;
+    };
+    let Ok((stream, _)) = listener.accept().await else {
+      continue;
+    };
+    tokio::spawn(async move {
+      serve_stream(stream).await;
+      drop(permit);
+    });
+  }
+}
+
+//the cert and key from the [global.tls] section of Rocket.toml, either paths or the pem itself
+fn tls_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, io::Error> {
+  let certs_pem = tls.certs().either(std::fs::read, |bytes| Ok(bytes.to_vec()))?;
+  let key_pem = tls.key().either(std::fs::read, |bytes| Ok(bytes.to_vec()))?;
+  let certs = rustls_pemfile::certs(&mut certs_pem.as_slice())?.into_iter().map(Certificate).collect();
+  //mkcert gives pkcs8, but accept the other kinds rocket does too
+  let key = rustls_pemfile::read_all(&mut key_pem.as_slice())?.into_iter().find_map(|item| match item {
+    rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+    _ => None,
+  }).ok_or_else(|| io::Error::other("no private key found"))?;
+  let config = ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(io::Error::other)?;
+  Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+//same framing as tcp once the handshake is done (rfc 7858 section 3.3)
+pub async fn serve_tls(tls: Option<TlsConfig>) {
+  let Some(tls) = tls else {
+    println!("No TLS configured, not serving DNS over TLS");
+    return;
+  };
+  let acceptor = match tls_acceptor(&tls) {
+    Ok(acceptor) => acceptor,
+    Err(e) => {
+      println!("Could not load the TLS cert and key for DNS over TLS: {}", e);
+      return;
+    },
+  };
+  let listener = match TcpListener::bind(TLS_ADDRESS).await {
+    Ok(listener) => listener,
+    Err(e) => {
+      println!("Could not bind DNS over TLS to {}: {}", TLS_ADDRESS, e);
+      return;
+    },
+  };
+  println!("DNS over TLS listening on {}", TLS_ADDRESS);
+  let connections = Arc::new(Semaphore::new(TCP_MAX_CONNECTIONS));
+  loop {
+    let Ok(permit) = Arc::clone(&connections).acquire_owned().await else {
+      return;
+    };
+    let Ok((stream, _)) = listener.accept().await else {
+      continue;
+    };
+    let acceptor = acceptor.clone();
+    tokio::spawn(async move {
+      //a client that never finishes the handshake is just as idle as one that never sends a query
+      if let Ok(Ok(stream)) = timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream)).await {
+        serve_stream(stream).await;
+      }
+      drop(permit);
+    });
+  }
+}
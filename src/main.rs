@@ -1,18 +1,26 @@
 use std::path::PathBuf;
 use std::convert::Infallible;
 
-use rocket::{ get, options, post, Request, Responder };
+use rocket::{ get, options, post, FromForm, Request, Responder };
 use rocket::response::Redirect;
-use rocket::response::content::RawHtml;
-use rocket::http::{ ContentType, Header };
+use rocket::response::content::{ RawHtml, RawJson };
+use rocket::http::{ ContentType, Header, Status };
 use rocket::request::{ FromRequest, Outcome };
-use rocket::shield::{ Shield, Hsts };
+use rocket::shield::Shield;
+use rocket::fairing::AdHoc;
 use rocket::routes;
 
 mod utils;
-use crate::utils::extract_tld;
+use crate::utils::{ escape_html, extract_tld };
+mod message;
+mod cache;
 mod dns;
-use crate::dns::{ answer_dns_query, do_dns_query_for_bns, Answer, QueryResult, SELF_HOST };
+mod listeners;
+mod json;
+mod api;
+use crate::api::{ address_info, cache_stats, domain_info };
+use crate::json::{ answer_json_query, JsonAnswer };
+use crate::dns::{ answer_dns_query, do_dns_query_for_bns, is_bns_tld, Answer, BnsRecords, QueryResult, ResolveError, SELF_HOST };
 
 struct Host {
   pub host: String,
@@ -33,17 +41,41 @@ impl<'r> FromRequest<'r> for Host {
   }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Responder)]
 enum MaybeRedirect<R> {
   RawHtml(RawHtml<R>),
   Redirect(Redirect),
 }
 
-async fn handle_redirect(path: Option<PathBuf>, host: Host) -> MaybeRedirect<&'static str> {
+//what the landing page says about the domain looked up on it, eg: /?domain=prussia.ban
+//burned domains are NXDOMAIN, so a browser never gets to us for them, this is the only place anyone sees why
+async fn domain_status(domain: &str) -> String {
+  let (domain_name, tld) = extract_tld(domain);
+  let status = if !is_bns_tld(tld) {
+    "is not a BNS domain".to_string()
+  } else if domain_name.is_empty() {
+    "is a BNS TLD, not a domain".to_string()
+  } else {
+    match do_dns_query_for_bns(domain_name.to_string(), tld.to_string()).await {
+      Ok(_) => "resolves".to_string(),
+      Err(ResolveError::Burned) => "was burned by its owner, so it no longer resolves to anything".to_string(),
+      Err(ResolveError::NotRegistered) => "is not registered".to_string(),
+      Err(error) => format!("could not be resolved right now ({})", error.extended_error().1),
+    }
+  };
+  format!(r#"<p><code>{0}</code> {1}. <a href="/api/domain/{0}">Details</a></p>"#, escape_html(domain), escape_html(&status))
+}
+
+async fn handle_redirect(_path: Option<PathBuf>, domain: Option<&str>, host: Host) -> MaybeRedirect<String> {
   let host = host.host;
   println!("HOST {}", host);
   if host == SELF_HOST {
-    MaybeRedirect::RawHtml(RawHtml(r#"<!DOCTYPE html>
+    let domain_status = match domain {
+      Some(domain) if !domain.is_empty() => domain_status(domain).await,
+      _ => String::new(),
+    };
+    MaybeRedirect::RawHtml(RawHtml(format!(r#"<!DOCTYPE html>
 <html lang="en">
   <head>
     <meta charset="UTF-8" />
@@ -53,12 +85,17 @@ async fn handle_redirect(path: Option<PathBuf>, host: Host) -> MaybeRedirect<&'s
   <body>
     <h1>Hello, world! This is the BNS DoH POC.</h1>
     <p>Set the DoH URL in your browser's settings to <code>https://127.0.0.1/dns-query</code>. Then try going to <a href="http://prussia.ban">http://prussia.ban</a> and <a href="https://prussia.ban.k">https://prussia.ban.k</a></p>
+    <form method="get" action="/">
+      <label>Look up a BNS domain: <input name="domain" placeholder="prussia.ban" value="{0}" /></label>
+      <button type="submit">Look up</button>
+    </form>
+    {1}
   </body>
-</html>"#))
+</html>"#, escape_html(domain.unwrap_or_default()), domain_status)))
   } else {
     let (domain_name, tld) = extract_tld(&host);
     //todo: instead of unwrap_or(0) should reject the request or something
-    MaybeRedirect::Redirect(if let QueryResult::A(_, Some(redirect)) = do_dns_query_for_bns(domain_name.to_string(), tld.to_string()).await {
+    MaybeRedirect::Redirect(if let Ok(QueryResult::Records(BnsRecords { redirect: Some(redirect), .. })) = do_dns_query_for_bns(domain_name.to_string(), tld.to_string()).await {
       //todo: add path
       Redirect::to(redirect)
     } else {
@@ -68,14 +105,15 @@ async fn handle_redirect(path: Option<PathBuf>, host: Host) -> MaybeRedirect<&'s
   }
 }
 
-#[get("/")]
-async fn handle_redirect_1(host: Host) -> MaybeRedirect<&'static str> {
-  handle_redirect(None, host).await
+#[get("/?<domain>")]
+async fn handle_redirect_1(domain: Option<&str>, host: Host) -> MaybeRedirect<String> {
+  handle_redirect(None, domain, host).await
 }
 
-#[get("/<path..>")]
-async fn handle_redirect_2(path: PathBuf, host: Host) -> MaybeRedirect<&'static str> {
-  handle_redirect(Some(path), host).await
+//catch all, so has to be ranked after everything else (including handle_dns_json)
+#[get("/<path..>", rank = 3)]
+async fn handle_redirect_2(path: PathBuf, host: Host) -> MaybeRedirect<String> {
+  handle_redirect(Some(path), None, host).await
 }
 
 #[derive(Responder)]
@@ -103,22 +141,62 @@ async fn handle_dns_get(dns: &str) -> Answer {
   }
 }
 
+//google/cloudflare style json, eg: /dns-query?name=prussia.ban&type=AAAA
+#[derive(FromForm)]
+struct JsonQuery<'r> {
+  name: &'r str,
+  #[field(name = "type")]
+  qtype: Option<&'r str>,
+}
+
+//ranked after handle_dns_get, so ?dns= always means wire format
+#[get("/dns-query?<query..>", format = "application/dns-json", rank = 2)]
+async fn handle_dns_json(query: JsonQuery<'_>) -> JsonAnswer {
+  answer_json_query(query.name, query.qtype).await
+}
+
 #[post("/dns-query", format = "application/dns-message", data = "<dns>")]
 async fn handle_dns_post(dns: Vec<u8>) -> Answer {
   answer_dns_query(dns, 0).await
 }
 
+//everything the resolver sees for a domain, eg: /api/domain/prussia.ban
+#[get("/api/domain/<domain>")]
+async fn handle_domain_info(domain: &str) -> (Status, RawJson<String>) {
+  domain_info(domain).await
+}
+
+//the banano address a domain resolves to, eg: /api/address/prussia.ban
+#[get("/api/address/<domain>")]
+async fn handle_address_info(domain: &str) -> (Status, RawJson<String>) {
+  address_info(domain).await
+}
+
+#[get("/api/cache")]
+fn handle_cache_stats() -> RawJson<String> {
+  cache_stats()
+}
+
 #[rocket::launch]
 async fn rocket() -> _ {
   //let shield = Shield::default().disable::<Hsts>();
   let shield = Shield::new();
-  rocket::build().attach(shield).mount("/", routes![
+  //plain dns listeners run alongside the DoH server, on the same runtime
+  let listeners = AdHoc::on_liftoff("DNS Listeners", |rocket| Box::pin(async {
+    rocket::tokio::spawn(listeners::serve_udp());
+    rocket::tokio::spawn(listeners::serve_tcp());
+    rocket::tokio::spawn(listeners::serve_tls(rocket.config().tls.clone()));
+  }));
+  rocket::build().attach(shield).attach(listeners).mount("/", routes![
     handle_redirect_1,
     handle_redirect_2,
     handle_dns_options,
     handle_dns_get,
+    handle_dns_json,
     handle_dns_post,
+    handle_domain_info,
+    handle_address_info,
+    handle_cache_stats,
   ])
 }
 
-//https://developers.cloudflare.com/cloudflare-one/connections/connect-networks/do-more-with-tunnels/local-management/create-local-tunnel/
@@ -0,0 +1,769 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{ Ipv4Addr, Ipv6Addr };
+
+//rfc 1035 (section 3.2, section 4)
+//typed dns message, so answers can be built without counting offsets by hand
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_SVCB: u16 = 64;
+pub const TYPE_HTTPS: u16 = 65;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const OPCODE_QUERY: u8 = 0;
+
+//the header only has room for 4 bits, the rest go in the OPT (see Message::set_rcode)
+pub const RCODE_NOERROR: u16 = 0;
+pub const RCODE_FORMERR: u16 = 1;
+pub const RCODE_SERVFAIL: u16 = 2;
+pub const RCODE_NXDOMAIN: u16 = 3;
+pub const RCODE_NOTIMP: u16 = 4;
+pub const RCODE_REFUSED: u16 = 5;
+//rfc 6891 section 9
+pub const RCODE_BADVERS: u16 = 16;
+
+//rfc 7830
+pub const EDNS_OPTION_PADDING: u16 = 12;
+//rfc 8914, extended dns errors. INFO-CODE (16 bits), then EXTRA-TEXT (utf-8)
+pub const EDNS_OPTION_EDE: u16 = 15;
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_STALE_ANSWER: u16 = 3;
+pub const EDE_PROHIBITED: u16 = 18;
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+pub const EDE_NETWORK_ERROR: u16 = 23;
+pub const EDE_INVALID_DATA: u16 = 24;
+
+//SvcParamKeys (rfc 9460 section 14.3.2)
+pub const SVC_PARAM_MANDATORY: u16 = 0;
+pub const SVC_PARAM_ALPN: u16 = 1;
+pub const SVC_PARAM_NO_DEFAULT_ALPN: u16 = 2;
+pub const SVC_PARAM_PORT: u16 = 3;
+pub const SVC_PARAM_IPV4HINT: u16 = 4;
+pub const SVC_PARAM_ECH: u16 = 5;
+pub const SVC_PARAM_IPV6HINT: u16 = 6;
+
+//names are at most 255 bytes on the wire, labels at most 63
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+//compression pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+  //ran off the end of the message
+  Truncated,
+  //label type 01 or 10 (reserved), or label too long
+  BadLabel,
+  //compression pointer that does not point strictly backwards
+  PointerLoop,
+  NameTooLong,
+  //rdata did not match the rdlength
+  BadRdata,
+  //bytes left over after all the sections in the header counts
+  TrailingBytes,
+}
+
+//labels are kept as raw bytes so the case the client sent is preserved
+#[derive(Clone, Debug, Default)]
+pub struct Name {
+  labels: Vec<Vec<u8>>,
+}
+
+impl Name {
+  pub fn root() -> Name {
+    Name { labels: Vec::new() }
+  }
+
+  pub fn from_labels(labels: Vec<Vec<u8>>) -> Name {
+    Name { labels }
+  }
+
+  //"prussia.ban" or "prussia.ban." (trailing dot ignored)
+  pub fn from_dotted(host: &str) -> Result<Name, ParseError> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+      return Ok(Name::root());
+    }
+    let mut labels = Vec::new();
+    for label in host.split('.') {
+      if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err(ParseError::BadLabel);
+      }
+      labels.push(label.as_bytes().to_vec());
+    }
+    let name = Name { labels };
+    if name.wire_len() > MAX_NAME_LEN {
+      return Err(ParseError::NameTooLong);
+    }
+    Ok(name)
+  }
+
+  pub fn labels(&self) -> &[Vec<u8>] {
+    &self.labels
+  }
+
+  //length of the uncompressed name, including the root label
+  pub fn wire_len(&self) -> usize {
+    self.labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1
+  }
+
+  //lowercased uncompressed wire bytes of the suffix starting at label `from`, used as the compression key
+  fn suffix_key(&self, from: usize) -> Vec<u8> {
+    let mut key = Vec::new();
+    for label in &self.labels[from..] {
+      key.push(label.len() as u8);
+      key.extend(label.iter().map(|c| c.to_ascii_lowercase()));
+    }
+    key
+  }
+}
+
+//names compare case insensitively (rfc 1035 section 2.3.3)
+impl PartialEq for Name {
+  fn eq(&self, other: &Name) -> bool {
+    self.labels.len() == other.labels.len() && self.labels.iter().zip(&other.labels).all(|(a, b)| a.eq_ignore_ascii_case(b))
+  }
+}
+
+impl Eq for Name {}
+
+impl fmt::Display for Name {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let labels = self.labels.iter().map(|label| label.iter().map(|c| char::from(*c)).collect::<String>()).collect::<Vec<String>>();
+    write!(f, "{}", labels.join("."))
+  }
+}
+
+/*
+Flags (total 16 bits)
+QR (query: 0, reply: 1), 1 bit
+OPCODE (standard: 0, inverse: 1, status: 2), 4 bits (opcode in query is repeated in response)
+AA (if authorative answer for hostname), 1 bit
+TC (whether message was truncated), 1 bit
+RD (where recursion desired), 1 bit
+RA (in response, whether recursion available), 1 bit
+Z (reserved, AD and CD since rfc 4035), 3 bits
+RCODE (response code, NOERROR: 0, FORM(at)ERR: 1, SERVFAIL: 2, NXDOMAIN: 3), 4 bits
+*/
+//the four section counts are not stored, they come from the lengths of the sections in Message
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Header {
+  pub id: u16,
+  pub qr: bool,
+  pub opcode: u8,
+  pub aa: bool,
+  pub tc: bool,
+  pub rd: bool,
+  pub ra: bool,
+  pub z: u8,
+  pub rcode: u8,
+}
+
+impl Header {
+  fn flags(&self) -> u16 {
+    (u16::from(self.qr) << 15)
+      | (u16::from(self.opcode & 0xF) << 11)
+      | (u16::from(self.aa) << 10)
+      | (u16::from(self.tc) << 9)
+      | (u16::from(self.rd) << 8)
+      | (u16::from(self.ra) << 7)
+      | (u16::from(self.z & 0x7) << 4)
+      | u16::from(self.rcode & 0xF)
+  }
+
+  fn from_flags(id: u16, flags: u16) -> Header {
+    Header {
+      id,
+      qr: flags & (1 << 15) != 0,
+      opcode: ((flags >> 11) & 0xF) as u8,
+      aa: flags & (1 << 10) != 0,
+      tc: flags & (1 << 9) != 0,
+      rd: flags & (1 << 8) != 0,
+      ra: flags & (1 << 7) != 0,
+      z: ((flags >> 4) & 0x7) as u8,
+      rcode: (flags & 0xF) as u8,
+    }
+  }
+}
+
+/*
+NAME, variable length
+TYPE (of question. A, AAAA, MX, TXT, or special from 251-255, 255 is * [all records]), 16 bits
+CLASS (probably IN for internet), 16 bits
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Question {
+  pub name: Name,
+  pub qtype: u16,
+  pub qclass: u16,
+}
+
+//rfc 9460, HTTPS has the same rdata as SVCB
+//priority 0 is alias mode, anything else is service mode. a target of . (root) means the owner name
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvcbData {
+  pub priority: u16,
+  pub target: Name,
+  //(SvcParamKey, SvcParamValue wire bytes), has to be in increasing order of key
+  pub params: Vec<(u16, Vec<u8>)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RData {
+  A(Ipv4Addr),
+  Ns(Name),
+  Cname(Name),
+  Soa {
+    mname: Name,
+    rname: Name,
+    serial: u32,
+    refresh: u32,
+    retry: u32,
+    expire: u32,
+    minimum: u32,
+  },
+  Ptr(Name),
+  Mx {
+    preference: u16,
+    exchange: Name,
+  },
+  //each entry is one <character-string>, so at most 255 bytes
+  Txt(Vec<Vec<u8>>),
+  //rfc 3596
+  Aaaa(Ipv6Addr),
+  //rfc 2782
+  Srv {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: Name,
+  },
+  Svcb(SvcbData),
+  Https(SvcbData),
+  //rfc 6891, (OPTION-CODE, OPTION-DATA). see Edns for what the CLASS and TTL mean
+  Opt(Vec<(u16, Vec<u8>)>),
+  //any type we do not understand, kept as raw bytes (type, rdata)
+  Other(u16, Vec<u8>),
+}
+
+impl RData {
+  pub fn rtype(&self) -> u16 {
+    match self {
+      RData::A(_) => TYPE_A,
+      RData::Ns(_) => TYPE_NS,
+      RData::Cname(_) => TYPE_CNAME,
+      RData::Soa { .. } => TYPE_SOA,
+      RData::Ptr(_) => TYPE_PTR,
+      RData::Mx { .. } => TYPE_MX,
+      RData::Txt(_) => TYPE_TXT,
+      RData::Aaaa(_) => TYPE_AAAA,
+      RData::Srv { .. } => TYPE_SRV,
+      RData::Svcb(_) => TYPE_SVCB,
+      RData::Https(_) => TYPE_HTTPS,
+      RData::Opt(_) => TYPE_OPT,
+      RData::Other(rtype, _) => *rtype,
+    }
+  }
+}
+
+/*
+RR Format:
+NAME, variable length (see above in question section)
+TYPE (A, AAAA, MX, TXT, etc), 16 bits
+CLASS, 16 bits
+TTL (time record is valid for), 32 bits
+RDLENGTH (length of RDATA in bytes), 16 bits
+RDATA (additional RR specific data, see RDLENGTH), variable length
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceRecord {
+  pub name: Name,
+  pub class: u16,
+  pub ttl: u32,
+  pub rdata: RData,
+}
+
+impl ResourceRecord {
+  pub fn rtype(&self) -> u16 {
+    self.rdata.rtype()
+  }
+}
+
+/*
+The OPT pseudo-RR (rfc 6891 section 6.1), goes in the additional section
+NAME, always root
+CLASS, the udp payload size the sender can receive
+TTL, extended RCODE (upper 8 bits of the 12 bit rcode, 8 bits), VERSION (8 bits), DO (dnssec ok, 1 bit), Z (15 bits)
+RDATA, options
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns {
+  pub udp_payload_size: u16,
+  pub extended_rcode: u8,
+  pub version: u8,
+  pub dnssec_ok: bool,
+  pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Edns {
+  pub fn from_record(record: &ResourceRecord) -> Option<Edns> {
+    if let RData::Opt(options) = &record.rdata {
+      Some(Edns {
+        udp_payload_size: record.class,
+        extended_rcode: (record.ttl >> 24) as u8,
+        version: (record.ttl >> 16) as u8,
+        dnssec_ok: record.ttl & (1 << 15) != 0,
+        options: options.clone(),
+      })
+    } else {
+      None
+    }
+  }
+
+  pub fn to_record(&self) -> ResourceRecord {
+    ResourceRecord {
+      name: Name::root(),
+      class: self.udp_payload_size,
+      ttl: (u32::from(self.extended_rcode) << 24) | (u32::from(self.version) << 16) | (u32::from(self.dnssec_ok) << 15),
+      rdata: RData::Opt(self.options.clone()),
+    }
+  }
+
+  pub fn option(&self, code: u16) -> Option<&[u8]> {
+    self.options.iter().find(|(option_code, _)| *option_code == code).map(|(_, data)| data.as_slice())
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+  pub header: Header,
+  pub questions: Vec<Question>,
+  pub answers: Vec<ResourceRecord>,
+  //RRs that point toward authority
+  pub authorities: Vec<ResourceRecord>,
+  //RRs with additional information
+  pub additionals: Vec<ResourceRecord>,
+}
+
+impl Message {
+  pub fn decode(bytes: &[u8]) -> Result<Message, ParseError> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let id = decoder.u16()?;
+    let header = Header::from_flags(id, decoder.u16()?);
+    let qdcount = decoder.u16()?;
+    let ancount = decoder.u16()?;
+    let nscount = decoder.u16()?;
+    let arcount = decoder.u16()?;
+    //don't trust the counts for preallocation, a 12 byte message can claim 65535 questions
+    let mut questions = Vec::new();
+    for _ in 0..qdcount {
+      questions.push(Question {
+        name: decoder.name()?,
+        qtype: decoder.u16()?,
+        qclass: decoder.u16()?,
+      });
+    }
+    let answers = decoder.records(ancount)?;
+    let authorities = decoder.records(nscount)?;
+    let additionals = decoder.records(arcount)?;
+    if decoder.pos != bytes.len() {
+      return Err(ParseError::TrailingBytes);
+    }
+    Ok(Message {
+      header,
+      questions,
+      answers,
+      authorities,
+      additionals,
+    })
+  }
+
+  pub fn edns(&self) -> Option<Edns> {
+    self.additionals.iter().find_map(Edns::from_record)
+  }
+
+  //replaces the OPT, if there is one already
+  pub fn set_edns(&mut self, edns: Edns) {
+    self.additionals.retain(|record| record.rtype() != TYPE_OPT);
+    self.additionals.push(edns.to_record());
+  }
+
+  //the full 12 bit rcode, the lower 4 bits from the header and the rest from the OPT (if there is one)
+  pub fn rcode(&self) -> u16 {
+    let extended_rcode = self.edns().map_or(0, |edns| u16::from(edns.extended_rcode));
+    (extended_rcode << 4) | u16::from(self.header.rcode)
+  }
+
+  //rcodes over 15 are dropped to their lower 4 bits if there is no OPT to put the rest in
+  pub fn set_rcode(&mut self, rcode: u16) {
+    self.header.rcode = (rcode & 0xF) as u8;
+    if let Some(mut edns) = self.edns() {
+      edns.extended_rcode = (rcode >> 4) as u8;
+      self.set_edns(edns);
+    }
+  }
+
+  //rfc 7830, pads (with the padding option in the OPT) so the message is a multiple of block_size bytes long
+  //so the size of encrypted messages gives away less about what is in them. no OPT, no padding
+  pub fn encode_padded(&mut self, block_size: usize) -> Vec<u8> {
+    let Some(mut edns) = self.edns() else {
+      return self.encode();
+    };
+    edns.options.retain(|(code, _)| *code != EDNS_OPTION_PADDING);
+    self.set_edns(edns.clone());
+    //the OPT is always last and its name is always the root, so adding the option grows the message by exactly 4 + padding bytes
+    let unpadded_len = self.encode().len() + 4;
+    let padding = (block_size - unpadded_len % block_size) % block_size;
+    edns.options.push((EDNS_OPTION_PADDING, vec![0; padding]));
+    self.set_edns(edns);
+    self.encode()
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut encoder = Encoder { bytes: Vec::new(), names: HashMap::new() };
+    encoder.u16(self.header.id);
+    encoder.u16(self.header.flags());
+    encoder.u16(self.questions.len() as u16);
+    encoder.u16(self.answers.len() as u16);
+    encoder.u16(self.authorities.len() as u16);
+    encoder.u16(self.additionals.len() as u16);
+    for question in &self.questions {
+      encoder.name(&question.name, true);
+      encoder.u16(question.qtype);
+      encoder.u16(question.qclass);
+    }
+    for record in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+      encoder.record(record);
+    }
+    encoder.bytes
+  }
+}
+
+struct Decoder<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl Decoder<'_> {
+  fn take(&mut self, len: usize) -> Result<&[u8], ParseError> {
+    let taken = self.bytes.get(self.pos..(self.pos + len)).ok_or(ParseError::Truncated)?;
+    self.pos += len;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, ParseError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, ParseError> {
+    let b = self.take(2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, ParseError> {
+    let b = self.take(4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  /*
+  - NAME is divided into multiple zones/labels (eg: en, wikipedia, org), each zone has 8 bits indicating how many bytes the length is (eg, en is 2 bytes), then the name in ascii, repeated), 0 indicates the NAME is done. Eg: 2 en 9 wikipedia 3 org 0
+  - NAME or a zone can also be a pointer (16 bits) if first two bits are 11, other 14 bits are an offset
+  */
+  fn name(&mut self) -> Result<Name, ParseError> {
+    let mut labels = Vec::new();
+    let mut wire_len = 1;
+    let mut pos = self.pos;
+    //every pointer has to jump to before where the last jump landed, so following them always terminates
+    let mut limit = self.pos;
+    let mut jumped = false;
+    loop {
+      let length = *self.bytes.get(pos).ok_or(ParseError::Truncated)?;
+      match length & 0xC0 {
+        0x00 => {
+          pos += 1;
+          if length == 0 {
+            //the last ., ended
+            break;
+          }
+          let label = self.bytes.get(pos..(pos + usize::from(length))).ok_or(ParseError::Truncated)?;
+          wire_len += label.len() + 1;
+          if wire_len > MAX_NAME_LEN {
+            return Err(ParseError::NameTooLong);
+          }
+          labels.push(label.to_vec());
+          pos += label.len();
+        },
+        0xC0 => {
+          let low = *self.bytes.get(pos + 1).ok_or(ParseError::Truncated)?;
+          let offset = (usize::from(length & 0x3F) << 8) | usize::from(low);
+          if offset >= limit {
+            return Err(ParseError::PointerLoop);
+          }
+          if !jumped {
+            //the name continues elsewhere, but in this spot it ends right after the pointer
+            self.pos = pos + 2;
+            jumped = true;
+          }
+          limit = offset;
+          pos = offset;
+        },
+        _ => return Err(ParseError::BadLabel),
+      }
+    }
+    if !jumped {
+      self.pos = pos;
+    }
+    Ok(Name { labels })
+  }
+
+  fn svcb(&mut self, end: usize) -> Result<SvcbData, ParseError> {
+    let priority = self.u16()?;
+    let target = self.name()?;
+    let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+    while self.pos < end {
+      let key = self.u16()?;
+      //keys have to be strictly increasing, otherwise the rdata is malformed (rfc 9460 section 2.2)
+      if let Some((last_key, _)) = params.last() && *last_key >= key {
+        return Err(ParseError::BadRdata);
+      }
+      let length = usize::from(self.u16()?);
+      params.push((key, self.take(length)?.to_vec()));
+    }
+    Ok(SvcbData { priority, target, params })
+  }
+
+  fn records(&mut self, count: u16) -> Result<Vec<ResourceRecord>, ParseError> {
+    let mut records = Vec::new();
+    for _ in 0..count {
+      records.push(self.record()?);
+    }
+    Ok(records)
+  }
+
+  fn record(&mut self) -> Result<ResourceRecord, ParseError> {
+    let name = self.name()?;
+    let rtype = self.u16()?;
+    let class = self.u16()?;
+    let ttl = self.u32()?;
+    let rdlength = usize::from(self.u16()?);
+    let end = self.pos + rdlength;
+    if end > self.bytes.len() {
+      return Err(ParseError::Truncated);
+    }
+    let rdata = match rtype {
+      TYPE_A => {
+        let b = self.take(rdlength)?;
+        if b.len() != 4 {
+          return Err(ParseError::BadRdata);
+        }
+        RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+      },
+      TYPE_NS => RData::Ns(self.name()?),
+      TYPE_CNAME => RData::Cname(self.name()?),
+      TYPE_SOA => RData::Soa {
+        mname: self.name()?,
+        rname: self.name()?,
+        serial: self.u32()?,
+        refresh: self.u32()?,
+        retry: self.u32()?,
+        expire: self.u32()?,
+        minimum: self.u32()?,
+      },
+      TYPE_PTR => RData::Ptr(self.name()?),
+      TYPE_MX => RData::Mx {
+        preference: self.u16()?,
+        exchange: self.name()?,
+      },
+      TYPE_TXT => {
+        let mut strings = Vec::new();
+        while self.pos < end {
+          let length = usize::from(self.u8()?);
+          strings.push(self.take(length)?.to_vec());
+        }
+        RData::Txt(strings)
+      },
+      TYPE_AAAA => {
+        let b: [u8; 16] = self.take(rdlength)?.try_into().map_err(|_| ParseError::BadRdata)?;
+        RData::Aaaa(Ipv6Addr::from(b))
+      },
+      TYPE_SRV => RData::Srv {
+        priority: self.u16()?,
+        weight: self.u16()?,
+        port: self.u16()?,
+        //not supposed to be compressed, but rfc 3597 section 4 says to accept it anyways
+        target: self.name()?,
+      },
+      TYPE_SVCB => RData::Svcb(self.svcb(end)?),
+      TYPE_HTTPS => RData::Https(self.svcb(end)?),
+      TYPE_OPT => {
+        let mut options = Vec::new();
+        while self.pos < end {
+          let code = self.u16()?;
+          let length = usize::from(self.u16()?);
+          options.push((code, self.take(length)?.to_vec()));
+        }
+        RData::Opt(options)
+      },
+      _ => RData::Other(rtype, self.take(rdlength)?.to_vec()),
+    };
+    //names inside rdata are variable length, so check they did not run over (or under) rdlength
+    if self.pos != end {
+      return Err(ParseError::BadRdata);
+    }
+    Ok(ResourceRecord {
+      name,
+      class,
+      ttl,
+      rdata,
+    })
+  }
+}
+
+struct Encoder {
+  bytes: Vec<u8>,
+  //lowercased wire form of every name suffix written so far, and where it starts (rfc 1035 section 4.1.4)
+  names: HashMap<Vec<u8>, usize>,
+}
+
+impl Encoder {
+  fn u16(&mut self, n: u16) {
+    self.bytes.extend_from_slice(&n.to_be_bytes());
+  }
+
+  fn u32(&mut self, n: u32) {
+    self.bytes.extend_from_slice(&n.to_be_bytes());
+  }
+
+  fn name(&mut self, name: &Name, compress: bool) {
+    for (i, label) in name.labels().iter().enumerate() {
+      let key = name.suffix_key(i);
+      if compress && let Some(&offset) = self.names.get(&key) {
+        //first two bits 11 mark a pointer
+        self.u16(0xC000 | offset as u16);
+        return;
+      }
+      if self.bytes.len() <= MAX_POINTER_OFFSET {
+        self.names.entry(key).or_insert(self.bytes.len());
+      }
+      self.bytes.push(label.len() as u8);
+      self.bytes.extend_from_slice(label);
+    }
+    self.bytes.push(0);
+  }
+
+  fn record(&mut self, record: &ResourceRecord) {
+    self.name(&record.name, true);
+    self.u16(record.rtype());
+    self.u16(record.class);
+    self.u32(record.ttl);
+    //RDLENGTH is filled in once the rdata is written, since compression changes how long the names are
+    let rdlength_pos = self.bytes.len();
+    self.u16(0);
+    match &record.rdata {
+      RData::A(ip) => self.bytes.extend_from_slice(&ip.octets()),
+      RData::Ns(name) | RData::Cname(name) | RData::Ptr(name) => self.name(name, true),
+      RData::Soa { mname, rname, serial, refresh, retry, expire, minimum } => {
+        self.name(mname, true);
+        self.name(rname, true);
+        for n in [serial, refresh, retry, expire, minimum] {
+          self.u32(*n);
+        }
+      },
+      RData::Mx { preference, exchange } => {
+        self.u16(*preference);
+        self.name(exchange, true);
+      },
+      RData::Txt(strings) => {
+        for string in strings {
+          self.bytes.push(string.len() as u8);
+          self.bytes.extend_from_slice(string);
+        }
+      },
+      RData::Aaaa(ip) => self.bytes.extend_from_slice(&ip.octets()),
+      RData::Srv { priority, weight, port, target } => {
+        self.u16(*priority);
+        self.u16(*weight);
+        self.u16(*port);
+        //rfc 2782: "name compression is not to be used for this field"
+        self.name(target, false);
+      },
+      RData::Svcb(svcb) | RData::Https(svcb) => {
+        self.u16(svcb.priority);
+        //rfc 9460 section 2.2: TargetName is uncompressed
+        self.name(&svcb.target, false);
+        for (key, value) in &svcb.params {
+          self.u16(*key);
+          self.u16(value.len() as u16);
+          self.bytes.extend_from_slice(value);
+        }
+      },
+      RData::Opt(options) => {
+        for (code, data) in options {
+          self.u16(*code);
+          self.u16(data.len() as u16);
+          self.bytes.extend_from_slice(data);
+        }
+      },
+      RData::Other(_, rdata) => self.bytes.extend_from_slice(rdata),
+    }
+    let rdlength = (self.bytes.len() - rdlength_pos - 2) as u16;
+    self.bytes[rdlength_pos..(rdlength_pos + 2)].copy_from_slice(&rdlength.to_be_bytes());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn query(host: &str) -> Message {
+    Message {
+      header: Header { id: 0x1234, rd: true, ..Header::default() },
+      questions: vec![Question { name: Name::from_dotted(host).unwrap(), qtype: TYPE_A, qclass: CLASS_IN }],
+      ..Message::default()
+    }
+  }
+
+  #[test]
+  fn round_trip_preserves_case() {
+    let mut message = query("PrUsSia.BaN");
+    message.header.qr = true;
+    message.answers.push(ResourceRecord { name: Name::from_dotted("PrUsSia.BaN").unwrap(), class: CLASS_IN, ttl: 600, rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)) });
+    let bytes = message.encode();
+    let decoded = Message::decode(&bytes).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(decoded.questions[0].name.to_string(), "PrUsSia.BaN");
+    assert_eq!(decoded.encode(), bytes);
+  }
+
+  #[test]
+  fn self_pointer_is_a_loop() {
+    //one question, whose name is a pointer to itself (offset 12)
+    let bytes = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01];
+    assert_eq!(Message::decode(&bytes), Err(ParseError::PointerLoop));
+  }
+
+  #[test]
+  fn overclaimed_qdcount_is_truncated() {
+    let mut bytes = query("prussia.ban").encode();
+    bytes[5] = 2;
+    assert_eq!(Message::decode(&bytes), Err(ParseError::Truncated));
+  }
+
+  #[test]
+  fn trailing_bytes_are_rejected() {
+    let mut bytes = query("prussia.ban").encode();
+    bytes.push(0);
+    assert_eq!(Message::decode(&bytes), Err(ParseError::TrailingBytes));
+  }
+}
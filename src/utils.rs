@@ -1,4 +1,10 @@
+use std::net::{ Ipv4Addr, Ipv6Addr };
+
 use crypto_bigint::{ U512, Checked, NonZero };
+use blake2::Blake2bVar;
+use blake2::digest::{ Update, VariableOutput };
+
+use crate::message::{ Name, SvcbData, SVC_PARAM_MANDATORY, SVC_PARAM_ALPN, SVC_PARAM_NO_DEFAULT_ALPN, SVC_PARAM_PORT, SVC_PARAM_IPV4HINT, SVC_PARAM_ECH, SVC_PARAM_IPV6HINT };
 
 pub fn to_binary(num: impl std::fmt::Binary, pad: bool) -> String {
   let mut unpadded = format!("{:b}", num);
@@ -23,12 +29,11 @@ pub fn binary_to_u8(binary_chars: &str) -> u8 {
       total += 2_u8.pow((8 - i).try_into().unwrap());
     }
   }
-  return total;
+  total
 }
 
 const B64_CHARS: [char; 64] = ['A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '-', '_'];
 
-//seems to work but might have extra 0s at end
 pub fn b64_url_to_u8_vec(b64: &str) -> Result<Vec<u8>, ()> {
   let mut u8_vec = Vec::new();
 
@@ -47,42 +52,79 @@ pub fn b64_url_to_u8_vec(b64: &str) -> Result<Vec<u8>, ()> {
     }
   }
 
-  let mod_b = binary.len() % 8;
-  if binary.len() % 8 != 0 {
-    binary = binary + &"0".repeat(8 - mod_b);
-  }
+  //leftover bits (less than 8) are just filler from the b64 encoding, not a byte
+  //padding them into a byte instead leaves an extra 0 at the end, which the dns message parser rejects
+  binary.truncate(binary.len() - binary.len() % 8);
 
   //turn each 8 bytes of binary into a u8, add to vec
-  //(if last one is not 8, pad with zeroes)
   for i in 0..(binary.len() / 8) {
     u8_vec.push(binary_to_u8(binary.get((i * 8)..(i * 8 + 8)).unwrap()));
   }
 
-  return Ok(u8_vec);
+  Ok(u8_vec)
 }
 
-//put in valid input or else! todo: change it to Result<> and handle errors
-pub fn ip_string_to_u8_array(ip: &str) -> [u8; 4] {
-  let mut ip_array = [0; 4];
-  let mut ip_split = ip.split(".");
-  for i in 0..4 {
-    ip_array[i] = ip_split.next().unwrap().parse::<u8>().unwrap();
+//regular (not url) b64, which uses + and / instead of - and _
+pub fn b64_to_u8_vec(b64: &str) -> Result<Vec<u8>, ()> {
+  if b64.contains(['-', '_']) {
+    return Err(());
   }
-  ip_array
+  b64_url_to_u8_vec(&b64.replace("+", "-").replace("/", "_"))
+}
+
+//regular b64 (with padding), the other way
+pub fn u8_vec_to_b64(bytes: &[u8]) -> String {
+  let mut b64 = String::new();
+  for chunk in bytes.chunks(3) {
+    let mut group = [0u8; 3];
+    group[..chunk.len()].copy_from_slice(chunk);
+    let bits = (u32::from(group[0]) << 16) | (u32::from(group[1]) << 8) | u32::from(group[2]);
+    //each byte in the chunk is another 8 bits, so needs one more char than the byte count
+    for i in 0..=chunk.len() {
+      b64.push(B64_CHARS[((bits >> (18 - i * 6)) & 0x3F) as usize]);
+    }
+    for _ in chunk.len()..3 {
+      b64.push('=');
+    }
+  }
+  b64.replace("-", "+").replace("_", "/")
+}
+
+pub fn escape_html(text: &str) -> String {
+  text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
 }
 
 pub fn extract_tld(host: &str) -> (&str, &str) {
   let n = host.split(".").count();
   let mut tld = host.split(".").last().unwrap();
-  let mut domain = &host[..(host.len() - tld.len()).checked_sub(1).unwrap_or(0)];
+  let mut domain = &host[..host.len().saturating_sub(tld.len() + 1)];
   //because https certs for *.ban won't work but will work for *.ban.k
-  if tld == "k" {
-    tld = host.split(".").nth(n - 2).unwrap_or("k");
-    domain = &domain[..(domain.len() - tld.len()).checked_sub(1).unwrap_or(0)];
+  if tld.eq_ignore_ascii_case("k") {
+    tld = n.checked_sub(2).and_then(|i| host.split(".").nth(i)).unwrap_or("k");
+    domain = &domain[..domain.len().saturating_sub(tld.len() + 1)];
   }
   (domain, tld)
 }
 
+//"_matrix._tcp.prussia" -> ("_matrix._tcp", "prussia"), the leading labels that start with an underscore
+pub fn split_service_labels(domain: &str) -> (&str, &str) {
+  let mut service_len = 0;
+  for label in domain.split(".") {
+    if !label.starts_with("_") {
+      break;
+    }
+    service_len += label.len() + 1;
+  }
+  if service_len == 0 {
+    ("", domain)
+  } else if service_len > domain.len() {
+    //all of it
+    (domain, "")
+  } else {
+    (&domain[..(service_len - 1)], &domain[service_len..])
+  }
+}
+
 //very forgiving, for now
 pub fn parse_a_record(a_record: &str) -> Option<[u8; 4]> {
   let l = a_record.split(".").count();
@@ -103,7 +145,152 @@ pub fn parse_a_record(a_record: &str) -> Option<[u8; 4]> {
   }
 }
 
-const BASE58_CHARS: &'static str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+//anything std accepts, eg: "2001:db8::1"
+pub fn parse_aaaa_record(aaaa_record: &str) -> Option<[u8; 16]> {
+  aaaa_record.trim().parse::<Ipv6Addr>().ok().map(|ip| ip.octets())
+}
+
+//TXT RDATA is a list of <character-string>s, each at most 255 bytes (rfc 1035 section 3.3.14)
+//so longer text is chunked, clients join the chunks back together
+pub fn split_txt_record(txt_record: &str) -> Vec<Vec<u8>> {
+  if txt_record.is_empty() {
+    return vec![Vec::new()];
+  }
+  txt_record.as_bytes().chunks(255).map(|chunk| chunk.to_vec()).collect()
+}
+
+fn svc_param_key(key: &str) -> Option<u16> {
+  match key {
+    "mandatory" => Some(SVC_PARAM_MANDATORY),
+    "alpn" => Some(SVC_PARAM_ALPN),
+    "no-default-alpn" => Some(SVC_PARAM_NO_DEFAULT_ALPN),
+    "port" => Some(SVC_PARAM_PORT),
+    "ipv4hint" => Some(SVC_PARAM_IPV4HINT),
+    "ech" => Some(SVC_PARAM_ECH),
+    "ipv6hint" => Some(SVC_PARAM_IPV6HINT),
+    //key65333 etc
+    _ => key.strip_prefix("key")?.parse::<u16>().ok(),
+  }
+}
+
+//the zone file presentation format (rfc 9460 section 2.1), eg: "1 . alpn=h3,h2 port=443 ipv4hint=1.2.3.4 ipv6hint=::1 ech=AEX+/..."
+//a target of . means the domain itself
+pub fn parse_svcb_record(svcb_record: &str) -> Option<SvcbData> {
+  let mut parts = svcb_record.split_whitespace();
+  let priority = parts.next()?.parse::<u16>().ok()?;
+  let target = Name::from_dotted(parts.next()?).ok()?;
+  let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+  for param in parts {
+    let (key, value) = param.split_once("=").unwrap_or((param, ""));
+    let key = svc_param_key(key)?;
+    if value.is_empty() != (key == SVC_PARAM_NO_DEFAULT_ALPN) {
+      //only no-default-alpn has no value
+      return None;
+    }
+    let value = match key {
+      SVC_PARAM_MANDATORY => {
+        let mut keys = value.split(",").map(svc_param_key).collect::<Option<Vec<u16>>>()?;
+        keys.sort();
+        keys.iter().flat_map(|key| key.to_be_bytes()).collect()
+      },
+      //each alpn id is length prefixed, so has to be 1 to 255 bytes
+      SVC_PARAM_ALPN => value.split(",").map(|alpn| u8::try_from(alpn.len()).ok().filter(|len| *len > 0).map(|len| [vec![len], alpn.as_bytes().to_vec()].concat())).collect::<Option<Vec<Vec<u8>>>>()?.concat(),
+      SVC_PARAM_NO_DEFAULT_ALPN => Vec::new(),
+      SVC_PARAM_PORT => value.parse::<u16>().ok()?.to_be_bytes().to_vec(),
+      SVC_PARAM_IPV4HINT => value.split(",").map(parse_a_record).collect::<Option<Vec<[u8; 4]>>>()?.concat(),
+      SVC_PARAM_ECH => b64_to_u8_vec(value).ok()?,
+      SVC_PARAM_IPV6HINT => value.split(",").map(parse_aaaa_record).collect::<Option<Vec<[u8; 16]>>>()?.concat(),
+      //unknown keys are not really usable without the \ escapes, so just take the value as is
+      _ => value.as_bytes().to_vec(),
+    };
+    params.push((key, value));
+  }
+  //wire format needs them in order, and each key only once
+  params.sort_by_key(|(key, _)| *key);
+  if params.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+    return None;
+  }
+  //every key in mandatory has to be there (and not be mandatory itself, or listed twice) (rfc 9460 section 8)
+  if let Some((_, mandatory)) = params.iter().find(|(key, _)| *key == SVC_PARAM_MANDATORY) {
+    let keys: Vec<u16> = mandatory.chunks(2).map(|key| u16::from_be_bytes([key[0], key[1]])).collect();
+    if keys.windows(2).any(|pair| pair[0] == pair[1]) || keys.iter().any(|key| *key == SVC_PARAM_MANDATORY || !params.iter().any(|(param_key, _)| param_key == key)) {
+      return None;
+    }
+  }
+  //alias mode (priority 0) can't have params
+  if priority == 0 && !params.is_empty() {
+    return None;
+  }
+  Some(SvcbData { priority, target, params })
+}
+
+fn svc_param_name(key: u16) -> String {
+  match key {
+    SVC_PARAM_MANDATORY => "mandatory".to_string(),
+    SVC_PARAM_ALPN => "alpn".to_string(),
+    SVC_PARAM_NO_DEFAULT_ALPN => "no-default-alpn".to_string(),
+    SVC_PARAM_PORT => "port".to_string(),
+    SVC_PARAM_IPV4HINT => "ipv4hint".to_string(),
+    SVC_PARAM_ECH => "ech".to_string(),
+    SVC_PARAM_IPV6HINT => "ipv6hint".to_string(),
+    _ => format!("key{}", key),
+  }
+}
+
+//zone file <character-string> contents (rfc 1035 section 5.1), " and \ are escaped, anything unprintable is \DDD
+pub fn escape_character_string(bytes: &[u8]) -> String {
+  bytes.iter().map(|&c| match c {
+    b'"' | b'\\' => format!("\\{}", char::from(c)),
+    b' '..=b'~' => char::from(c).to_string(),
+    _ => format!("\\{:03}", c),
+  }).collect()
+}
+
+//the alpn ids are each length prefixed. None if they aren't, or one has a comma (which would need escaping)
+fn alpn_ids_to_string(value: &[u8]) -> Option<String> {
+  let mut alpn_ids = Vec::new();
+  let mut rest = value;
+  while let Some((&len, after)) = rest.split_first() {
+    let alpn_id = after.get(..usize::from(len))?;
+    if alpn_id.is_empty() || alpn_id.contains(&b',') {
+      return None;
+    }
+    alpn_ids.push(escape_character_string(alpn_id));
+    rest = &after[usize::from(len)..];
+  }
+  if alpn_ids.is_empty() {
+    return None;
+  }
+  Some(alpn_ids.join(","))
+}
+
+//the other way, back to the presentation format
+//values that don't decode like their key says they should are written in the generic key65333="..." form instead
+pub fn svcb_record_to_string(svcb: &SvcbData) -> String {
+  let mut parts = vec![svcb.priority.to_string(), format!("{}.", svcb.target)];
+  for (key, value) in &svcb.params {
+    let presented = match *key {
+      SVC_PARAM_MANDATORY if !value.is_empty() && value.len() % 2 == 0 => Some(value.chunks(2).map(|key| svc_param_name(u16::from_be_bytes([key[0], key[1]]))).collect::<Vec<String>>().join(",")),
+      SVC_PARAM_ALPN => alpn_ids_to_string(value),
+      SVC_PARAM_NO_DEFAULT_ALPN if value.is_empty() => {
+        parts.push(svc_param_name(*key));
+        continue;
+      },
+      SVC_PARAM_PORT if value.len() == 2 => Some(u16::from_be_bytes([value[0], value[1]]).to_string()),
+      SVC_PARAM_IPV4HINT if !value.is_empty() && value.len() % 4 == 0 => Some(value.chunks(4).map(|ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string()).collect::<Vec<String>>().join(",")),
+      SVC_PARAM_ECH if !value.is_empty() => Some(u8_vec_to_b64(value)),
+      SVC_PARAM_IPV6HINT if !value.is_empty() && value.len() % 16 == 0 => Some(value.chunks(16).map(|ip| Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).to_string()).collect::<Vec<String>>().join(",")),
+      _ => None,
+    };
+    match presented {
+      Some(presented) => parts.push(format!("{}={}", svc_param_name(*key), presented)),
+      None => parts.push(format!("key{}=\"{}\"", key, escape_character_string(value))),
+    }
+  }
+  parts.join(" ")
+}
+
+const BASE58_CHARS: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
 
 //this is so so horrible and terrible but i cannot be arsed otherwise right now
 //dumb
@@ -120,3 +307,105 @@ pub fn hex_to_base58(hex: String) -> String {
   }
   base58.chars().rev().collect::<String>()
 }
+
+const BANANO_ADDRESS_CHARS: &str = "13456789abcdefghijkmnopqrstuwxyz";
+
+//ban_ then 60 chars of nano's base32 (5 bits per char): 52 chars (260 bits) of public key, of which the first 4 bits are padding,
+//then 8 chars (40 bits) of checksum, which is the 5 byte blake2b of the public key, in reverse
+pub fn is_valid_banano_address(address: &str) -> bool {
+  let Some(encoded) = address.strip_prefix("ban_") else {
+    return false;
+  };
+  if encoded.len() != 60 {
+    return false;
+  }
+  let mut binary = String::new();
+  for c in encoded.chars() {
+    let Some(position) = BANANO_ADDRESS_CHARS.chars().position(|ac| ac == c) else {
+      return false;
+    };
+    binary += &format!("{:05b}", position);
+  }
+  let Some(binary) = binary.strip_prefix("0000") else {
+    return false;
+  };
+  let bytes: Vec<u8> = (0..(binary.len() / 8)).map(|i| binary_to_u8(&binary[(i * 8)..(i * 8 + 8)])).collect();
+  let (public_key, checksum) = bytes.split_at(32);
+  let mut hasher = Blake2bVar::new(5).unwrap();
+  hasher.update(public_key);
+  let mut hash = [0u8; 5];
+  hasher.finalize_variable(&mut hash).unwrap();
+  hash.reverse();
+  checksum == hash
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::message::{ Message, RData, ResourceRecord, CLASS_IN };
+
+  #[test]
+  fn extract_tld_with_k() {
+    assert_eq!(extract_tld("prussia.ban"), ("prussia", "ban"));
+    assert_eq!(extract_tld("prussia.ban.k"), ("prussia", "ban"));
+    assert_eq!(extract_tld("k"), ("", "k"));
+    assert_eq!(extract_tld("x.k"), ("", "x"));
+    assert_eq!(extract_tld("k."), ("k", ""));
+    assert_eq!(extract_tld(""), ("", ""));
+  }
+
+  const SVCB_RECORD: &str = "1 . alpn=h3,h2 port=443 ipv4hint=1.2.3.4 ipv6hint=::1";
+
+  #[test]
+  fn svcb_round_trip() {
+    let svcb = parse_svcb_record(SVCB_RECORD).unwrap();
+    assert_eq!(svcb.params, vec![
+      (SVC_PARAM_ALPN, b"\x02h3\x02h2".to_vec()),
+      (SVC_PARAM_PORT, vec![1, 187]),
+      (SVC_PARAM_IPV4HINT, vec![1, 2, 3, 4]),
+      (SVC_PARAM_IPV6HINT, Ipv6Addr::LOCALHOST.octets().to_vec()),
+    ]);
+    assert_eq!(svcb_record_to_string(&svcb), SVCB_RECORD);
+  }
+
+  #[test]
+  fn svcb_rdata_decodes() {
+    let svcb = parse_svcb_record(SVCB_RECORD).unwrap();
+    let message = Message {
+      answers: vec![ResourceRecord { name: Name::from_dotted("prussia.ban").unwrap(), class: CLASS_IN, ttl: 600, rdata: RData::Https(svcb.clone()) }],
+      ..Message::default()
+    };
+    let decoded = Message::decode(&message.encode()).unwrap();
+    assert_eq!(decoded.answers[0].rdata, RData::Https(svcb));
+  }
+
+  #[test]
+  fn invalid_svcb_records() {
+    //alias mode with params
+    assert!(parse_svcb_record("0 prussia.ban. alpn=h2").is_none());
+    //duplicate key
+    assert!(parse_svcb_record("1 . port=443 port=8443").is_none());
+    //mandatory key that isn't there
+    assert!(parse_svcb_record("1 . mandatory=port alpn=h2").is_none());
+    //empty alpn id
+    assert!(parse_svcb_record("1 . alpn=h2,").is_none());
+  }
+
+  #[test]
+  fn valid_banano_addresses() {
+    assert!(is_valid_banano_address("ban_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476goo"));
+    assert!(is_valid_banano_address("ban_3pa1m3g79i1h7uijugndjeytpmqbsg6hc19zm8m7foqygwos1mmcqmab91hh"));
+  }
+
+  #[test]
+  fn invalid_banano_addresses() {
+    //last character of the checksum changed
+    assert!(!is_valid_banano_address("ban_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476gop"));
+    //public key changed
+    assert!(!is_valid_banano_address("ban_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476goo".replace("1ka1", "1ka3").as_str()));
+    //nano prefix, too short, and a character that isn't in the alphabet (0)
+    assert!(!is_valid_banano_address("nano_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476goo"));
+    assert!(!is_valid_banano_address("ban_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476go"));
+    assert!(!is_valid_banano_address("ban_1ka1ium4pfue3uxtntqsrib8mumxgazsjf58gidh1xeo5te3whsq8z476go0"));
+  }
+}